@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS credentials (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    uuid CHAR(36) NOT NULL,
+    credential_id VARBINARY(1023) NOT NULL,
+    passkey JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+    user_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (credential_id),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    uuid CHAR(36) NOT NULL,
+    token_hash CHAR(64) NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+    user_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (token_hash),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE sessions
+    ADD COLUMN device_label VARCHAR(100) NULL,
+    ADD COLUMN ip VARCHAR(45) NULL,
+    ADD COLUMN last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
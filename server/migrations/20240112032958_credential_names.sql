@@ -0,0 +1,3 @@
+ALTER TABLE credentials
+    ADD COLUMN name VARCHAR(50) NOT NULL DEFAULT 'Passkey',
+    ADD COLUMN last_used_at TIMESTAMP NULL;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    code_hash VARCHAR(255) NOT NULL,
+    used_at TIMESTAMP NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    user_id INT UNSIGNED NOT NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1 @@
+CREATE INDEX stories_user_id_created_at ON stories (user_id, created_at);
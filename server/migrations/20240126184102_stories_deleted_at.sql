@@ -0,0 +1,3 @@
+ALTER TABLE stories ADD COLUMN deleted_at TIMESTAMP NULL;
+
+UPDATE stories SET deleted_at = updated_at WHERE deleted = TRUE;
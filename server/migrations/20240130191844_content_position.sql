@@ -0,0 +1,11 @@
+ALTER TABLE content ADD COLUMN position INT UNSIGNED NOT NULL DEFAULT 0;
+
+-- Existing content keeps the order it was created in.
+UPDATE content
+    JOIN (
+        SELECT id, ROW_NUMBER() OVER (PARTITION BY story_id ORDER BY id) - 1 AS ordinal
+        FROM content
+    ) AS ordered ON content.id = ordered.id
+    SET content.position = ordered.ordinal;
+
+CREATE INDEX content_story_id_position ON content (story_id, position);
@@ -0,0 +1 @@
+ALTER TABLE stories ADD COLUMN version INT UNSIGNED NOT NULL DEFAULT 1;
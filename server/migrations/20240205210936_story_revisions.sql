@@ -0,0 +1,10 @@
+CREATE TABLE story_revisions (
+    id INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    version INT UNSIGNED NOT NULL,
+    title VARCHAR(100) NOT NULL,
+    content JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    story_id INT UNSIGNED NOT NULL,
+    FOREIGN KEY (story_id) REFERENCES stories(id),
+    UNIQUE (story_id, version)
+);
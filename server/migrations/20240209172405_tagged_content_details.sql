@@ -0,0 +1,12 @@
+-- Tag untagged content details with their type and format version.
+-- Image details are the only ones with a `src`.
+UPDATE content
+    SET details = JSON_SET(
+        details,
+        '$.type', IF(JSON_CONTAINS_PATH(details, 'one', '$.src'), 'image', 'text'),
+        '$.v', 1
+    )
+    WHERE NOT JSON_CONTAINS_PATH(details, 'one', '$.type');
+
+-- `kind` was derived from debug output, match it to the tag instead.
+UPDATE content SET kind = JSON_UNQUOTE(JSON_EXTRACT(details, '$.type'));
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS media (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    uuid CHAR(36) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+    user_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (uuid),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
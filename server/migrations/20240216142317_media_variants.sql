@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS media_variants (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    size VARCHAR(16) NOT NULL,
+    width INT UNSIGNED NOT NULL,
+    height INT UNSIGNED NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    byte_size BIGINT UNSIGNED NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    media_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (media_id, size),
+    FOREIGN KEY (media_id) REFERENCES media(id)
+);
@@ -0,0 +1,11 @@
+ALTER TABLE media
+    ADD COLUMN captured_at DATETIME NULL,
+    ADD COLUMN latitude DOUBLE NULL,
+    ADD COLUMN longitude DOUBLE NULL,
+    ADD COLUMN orientation SMALLINT UNSIGNED NULL,
+    ADD COLUMN camera_make VARCHAR(100) NULL,
+    ADD COLUMN camera_model VARCHAR(100) NULL,
+    -- Copy of the upload without its metadata, served unless the owner opted in.
+    ADD COLUMN stripped_storage_key VARCHAR(255) NULL,
+    ADD COLUMN stripped_size BIGINT UNSIGNED NULL,
+    ADD COLUMN keep_metadata BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,19 @@
+-- Uploads are deduplicated per user by their SHA-256. Existing media is left unhashed.
+ALTER TABLE media
+    ADD COLUMN sha256 CHAR(64) NULL,
+    -- Number of content rows referring to the media.
+    ADD COLUMN ref_count INT UNSIGNED NOT NULL DEFAULT 0,
+    ADD UNIQUE (user_id, sha256);
+
+ALTER TABLE content
+    ADD COLUMN media_id INT UNSIGNED NULL,
+    ADD FOREIGN KEY (media_id) REFERENCES media(id);
+
+UPDATE content
+    JOIN stories ON content.story_id = stories.id
+    JOIN media ON media.uuid = JSON_UNQUOTE(JSON_EXTRACT(content.details, '$.media'))
+        AND media.user_id = stories.user_id
+    SET content.media_id = media.id;
+
+UPDATE media
+    SET ref_count = (SELECT COUNT(*) FROM content WHERE content.media_id = media.id);
@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS uploads (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    uuid CHAR(36) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    received BIGINT UNSIGNED NOT NULL DEFAULT 0,
+    sha256 CHAR(64) NOT NULL,
+    keep_metadata BOOLEAN NOT NULL DEFAULT FALSE,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+    user_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (uuid),
+    INDEX (expires_at),
+    FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS upload_chunks (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    byte_offset BIGINT UNSIGNED NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    storage_key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    upload_id INT UNSIGNED NOT NULL,
+
+    UNIQUE (upload_id, byte_offset),
+    FOREIGN KEY (upload_id) REFERENCES uploads(id)
+);
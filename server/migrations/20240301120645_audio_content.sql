@@ -0,0 +1,8 @@
+-- Read from audio and video when it's uploaded.
+ALTER TABLE media
+    ADD COLUMN duration_ms BIGINT UNSIGNED NULL,
+    ADD COLUMN codec VARCHAR(50) NULL;
+
+-- `kind` mirrors the `type` content details are tagged with.
+ALTER TABLE content
+    ADD CONSTRAINT content_kind CHECK (kind IN ('image', 'text', 'audio'));
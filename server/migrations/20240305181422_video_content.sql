@@ -0,0 +1,9 @@
+ALTER TABLE media
+    ADD COLUMN width INT UNSIGNED NULL,
+    ADD COLUMN height INT UNSIGNED NULL,
+    -- Frame extracted from video, when the server is configured to extract one.
+    ADD COLUMN poster_storage_key VARCHAR(255) NULL;
+
+ALTER TABLE content DROP CHECK content_kind;
+ALTER TABLE content
+    ADD CONSTRAINT content_kind CHECK (kind IN ('image', 'text', 'audio', 'video'));
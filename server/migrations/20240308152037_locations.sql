@@ -0,0 +1,16 @@
+-- A story's primary location. Kept apart from `stories` since a spatial index needs a NOT NULL column,
+-- so a story without a location simply has no row here.
+CREATE TABLE IF NOT EXISTS story_locations (
+    story_id INT UNSIGNED PRIMARY KEY,
+    position POINT NOT NULL SRID 4326,
+    place_name VARCHAR(255) NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+
+    SPATIAL INDEX (position),
+    FOREIGN KEY (story_id) REFERENCES stories(id)
+);
+
+ALTER TABLE content DROP CHECK content_kind;
+ALTER TABLE content
+    ADD CONSTRAINT content_kind CHECK (kind IN ('image', 'text', 'audio', 'video', 'location'));
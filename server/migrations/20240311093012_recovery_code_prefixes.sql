@@ -0,0 +1,6 @@
+-- The first group of a code, so redeeming one only verifies the hashes of codes that share it.
+-- Codes generated before this have no prefix and are verified like before.
+ALTER TABLE recovery_codes
+    ADD COLUMN code_prefix CHAR(4) NULL;
+
+CREATE INDEX recovery_codes_user_id_code_prefix ON recovery_codes (user_id, code_prefix);
@@ -0,0 +1,35 @@
+-- Coordinates of location content, copied out of its details so stories can be found on the map
+-- by their location blocks. Kept apart from `content` since a spatial index needs a NOT NULL column.
+CREATE TABLE IF NOT EXISTS content_locations (
+    content_id INT UNSIGNED PRIMARY KEY,
+    position POINT NOT NULL SRID 4326,
+    place_name VARCHAR(255) NOT NULL,
+
+    SPATIAL INDEX (position),
+    FOREIGN KEY (content_id) REFERENCES content(id)
+);
+
+INSERT INTO content_locations (content_id, position, place_name)
+SELECT
+    id,
+    ST_GeomFromText(
+        CONCAT('POINT(', JSON_EXTRACT(details, '$.longitude'), ' ', JSON_EXTRACT(details, '$.latitude'), ')'),
+        4326,
+        'axis-order=long-lat'
+    ),
+    JSON_UNQUOTE(JSON_EXTRACT(details, '$.place_name'))
+FROM content
+WHERE kind = 'location';
+
+-- The story's location at each revision, as `{"latitude", "longitude", "place_name"}`, NULL without one.
+ALTER TABLE story_revisions ADD COLUMN location JSON NULL AFTER content;
+
+-- Locations weren't recorded before, so earlier revisions keep the story's current location
+-- and restoring one leaves the location as it is.
+UPDATE story_revisions
+JOIN story_locations ON story_locations.story_id = story_revisions.story_id
+SET story_revisions.location = JSON_OBJECT(
+    'latitude', ST_Latitude(story_locations.position),
+    'longitude', ST_Longitude(story_locations.position),
+    'place_name', story_locations.place_name
+);
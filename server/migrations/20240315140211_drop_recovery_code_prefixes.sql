@@ -0,0 +1,6 @@
+-- Prefixes gave away a third of each code to anyone who could read the table.
+-- Redeeming a code verifies it against each of the user's unused hashes instead.
+DROP INDEX recovery_codes_user_id_code_prefix ON recovery_codes;
+
+ALTER TABLE recovery_codes
+    DROP COLUMN code_prefix;
@@ -0,0 +1,3 @@
+-- Set when ffmpeg fails or times out on a video, so it isn't queued for a poster again.
+ALTER TABLE media
+    ADD COLUMN poster_failed_at TIMESTAMP NULL;
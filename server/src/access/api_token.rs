@@ -0,0 +1,131 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::model;
+
+use super::{schema, AccessError, MemoryDb};
+
+#[async_trait]
+pub trait AccessApiToken {
+    async fn create_api_token(
+        &self,
+        user_id: u32,
+        name: String,
+        token_hash: String,
+        scope: model::Scope,
+    ) -> Result<model::ApiToken, AccessError>;
+    async fn get_api_token_by_hash(
+        &self,
+        token_hash: String,
+    ) -> Result<Option<model::ApiToken>, AccessError>;
+    async fn get_user_api_tokens(&self, user_id: u32) -> Result<Vec<model::ApiToken>, AccessError>;
+    async fn touch_api_token(&self, token_id: u32) -> Result<(), AccessError>;
+    async fn delete_api_token(&self, user_id: u32, token_uuid: Uuid) -> Result<bool, AccessError>;
+}
+
+#[async_trait]
+impl AccessApiToken for MemoryDb {
+    /// Creates a row in the `api_tokens` table.
+    /// Like sessions, only the hash of the token is stored.
+    async fn create_api_token(
+        &self,
+        user_id: u32,
+        name: String,
+        token_hash: String,
+        scope: model::Scope,
+    ) -> Result<model::ApiToken, AccessError> {
+        let mut conn = self.conn().await?;
+        let token_uuid = Uuid::new_v4();
+        let token_id = sqlx::query!(
+            "INSERT INTO api_tokens (uuid, name, token_hash, scope, user_id) VALUES (?, ?, ?, ?, ?)",
+            token_uuid.to_string(),
+            name,
+            token_hash,
+            scope.as_str(),
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_id();
+
+        let token = sqlx::query_as!(
+            schema::ApiToken,
+            "SELECT * FROM api_tokens WHERE id = ?",
+            token_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(token)
+    }
+
+    /// Returns the token matching the provided hash, if it hasn't been revoked.
+    async fn get_api_token_by_hash(
+        &self,
+        token_hash: String,
+    ) -> Result<Option<model::ApiToken>, AccessError> {
+        let mut conn = self.conn().await?;
+        let token = sqlx::query_as!(
+            schema::ApiToken,
+            "SELECT * FROM api_tokens WHERE token_hash = ?",
+            token_hash
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match token {
+            Some(token) => Ok(Some(token.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the user's tokens, newest first.
+    async fn get_user_api_tokens(&self, user_id: u32) -> Result<Vec<model::ApiToken>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::ApiToken,
+            "SELECT * FROM api_tokens WHERE user_id = ? ORDER BY created_at DESC, id DESC",
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut tokens = Vec::new();
+        for t in rows.into_iter() {
+            tokens.push(t.try_into()?);
+        }
+
+        Ok(tokens)
+    }
+
+    /// Records that a token was just used.
+    async fn touch_api_token(&self, token_id: u32) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE api_tokens SET last_used_at = CURRENT_TIMESTAMP WHERE id = ?",
+            token_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes one of the user's tokens, immediately revoking it.
+    ///
+    /// Returns false if the user has no token with the provided uuid.
+    async fn delete_api_token(&self, user_id: u32, token_uuid: Uuid) -> Result<bool, AccessError> {
+        let mut conn = self.conn().await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM api_tokens WHERE uuid = ? AND user_id = ?",
+            token_uuid.to_string(),
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,184 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+use webauthn_rs::prelude::Passkey;
+
+use crate::model;
+
+use super::{schema, AccessError, MemoryDb, Transactional};
+
+#[async_trait]
+pub trait AccessCredential {
+    async fn create_credential(
+        &self,
+        user_id: u32,
+        name: String,
+        passkey: &Passkey,
+    ) -> Result<model::Credential, AccessError>;
+    async fn get_user_credentials(
+        &self,
+        user_id: u32,
+    ) -> Result<Vec<model::Credential>, AccessError>;
+    async fn update_credential(
+        &self,
+        credential_id: u32,
+        passkey: &Passkey,
+    ) -> Result<(), AccessError>;
+    async fn rename_credential(
+        &self,
+        user_id: u32,
+        credential_uuid: Uuid,
+        name: String,
+    ) -> Result<bool, AccessError>;
+    async fn delete_credential(
+        &self,
+        user_id: u32,
+        credential_uuid: Uuid,
+    ) -> Result<bool, AccessError>;
+}
+
+#[async_trait]
+impl AccessCredential for MemoryDb {
+    /// Creates a row in the `credentials` table for a passkey produced by a completed
+    /// registration ceremony.
+    async fn create_credential(
+        &self,
+        user_id: u32,
+        name: String,
+        passkey: &Passkey,
+    ) -> Result<model::Credential, AccessError> {
+        let mut conn = self.conn().await?;
+        let credential_uuid = Uuid::new_v4();
+        let credential_id = passkey.cred_id().0.clone();
+        let passkey = serde_json::to_value(passkey).map_err(schema::SchemaError::from)?;
+
+        let credential_id = sqlx::query!(
+            "INSERT INTO credentials (uuid, name, credential_id, passkey, user_id) VALUES (?, ?, ?, ?, ?)",
+            credential_uuid.to_string(),
+            name,
+            credential_id,
+            passkey,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_id();
+
+        let credential = sqlx::query_as!(
+            schema::Credential,
+            "SELECT * FROM credentials WHERE id = ?",
+            credential_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(credential)
+    }
+
+    /// Returns every passkey registered to the specified user, oldest first.
+    async fn get_user_credentials(
+        &self,
+        user_id: u32,
+    ) -> Result<Vec<model::Credential>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::Credential,
+            "SELECT * FROM credentials WHERE user_id = ? ORDER BY created_at, id",
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut credentials = Vec::new();
+        for c in rows.into_iter() {
+            credentials.push(c.try_into()?);
+        }
+
+        Ok(credentials)
+    }
+
+    /// Replaces the stored passkey after it was used to authenticate,
+    /// e.g. to persist a bumped signature counter, and records when it was used.
+    async fn update_credential(
+        &self,
+        credential_id: u32,
+        passkey: &Passkey,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        let passkey = serde_json::to_value(passkey).map_err(schema::SchemaError::from)?;
+
+        sqlx::query!(
+            "UPDATE credentials SET passkey = ?, last_used_at = CURRENT_TIMESTAMP WHERE id = ?",
+            passkey,
+            credential_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Changes the friendly name of one of the user's passkeys.
+    ///
+    /// Returns false if the user has no passkey with the provided uuid.
+    async fn rename_credential(
+        &self,
+        user_id: u32,
+        credential_uuid: Uuid,
+        name: String,
+    ) -> Result<bool, AccessError> {
+        let mut conn = self.conn().await?;
+        let updated = sqlx::query!(
+            "UPDATE credentials SET name = ? WHERE uuid = ? AND user_id = ?",
+            name,
+            credential_uuid.to_string(),
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+        Ok(updated > 0)
+    }
+
+    /// Deletes one of the user's passkeys.
+    /// Refuses to delete the user's only remaining passkey, which would lock them out.
+    /// Runs in its own transaction, or joins the one already in progress.
+    ///
+    /// Returns false if nothing was deleted.
+    async fn delete_credential(
+        &self,
+        user_id: u32,
+        credential_uuid: Uuid,
+    ) -> Result<bool, AccessError> {
+        let tx = self.begin().await?;
+        let deleted = {
+            let mut conn = tx.conn().await?;
+
+            // Locks every passkey of the user, so concurrent deletes can't remove the last two.
+            let uuids = sqlx::query_scalar!(
+                "SELECT uuid FROM credentials WHERE user_id = ? FOR UPDATE",
+                user_id
+            )
+            .fetch_all(&mut *conn)
+            .await?;
+
+            let credential_uuid = credential_uuid.to_string();
+            if uuids.len() > 1 && uuids.contains(&credential_uuid) {
+                sqlx::query!(
+                    "DELETE FROM credentials WHERE uuid = ? AND user_id = ?",
+                    credential_uuid,
+                    user_id
+                )
+                .execute(&mut *conn)
+                .await?
+                .rows_affected()
+            } else {
+                0
+            }
+        };
+        tx.commit().await?;
+
+        Ok(deleted > 0)
+    }
+}
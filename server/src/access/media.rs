@@ -0,0 +1,413 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{auth::VerifiedUser, model};
+
+use super::{schema, AccessError, MemoryDb};
+
+#[async_trait]
+pub trait AccessMedia {
+    async fn create_media(
+        &self,
+        user_id: u32,
+        media_uuid: Uuid,
+        content_type: String,
+        size: u64,
+        storage_key: String,
+        sha256: String,
+        keep_metadata: bool,
+    ) -> Result<model::Media, AccessError>;
+    async fn update_media_metadata(
+        &self,
+        media_id: u32,
+        metadata: &model::MediaMetadata,
+        stripped: Option<(String, u64)>,
+    ) -> Result<(), AccessError>;
+    async fn update_media_probe(
+        &self,
+        media_id: u32,
+        probe: &model::MediaProbe,
+    ) -> Result<(), AccessError>;
+    async fn update_media_poster(
+        &self,
+        media_id: u32,
+        storage_key: String,
+    ) -> Result<(), AccessError>;
+    async fn get_media_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+    ) -> Result<model::Media, AccessError>;
+    async fn get_media_by_sha256(
+        &self,
+        user_id: u32,
+        sha256: &str,
+    ) -> Result<Option<model::Media>, AccessError>;
+    async fn get_stored_media_size(&self, user_id: u32) -> Result<u64, AccessError>;
+    async fn get_unprocessed_media(&self) -> Result<Vec<model::Media>, AccessError>;
+    async fn get_unreferenced_media_ids(
+        &self,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<u32>, AccessError>;
+    async fn touch_media(&self, media_id: u32) -> Result<(), AccessError>;
+    async fn mark_media_poster_failed(&self, media_id: u32) -> Result<(), AccessError>;
+    async fn delete_unreferenced_media(&self, media_id: u32) -> Result<Vec<String>, AccessError>;
+    async fn create_media_variant(
+        &self,
+        media_id: u32,
+        size: model::VariantSize,
+        width: u32,
+        height: u32,
+        content_type: String,
+        byte_size: u64,
+        storage_key: String,
+    ) -> Result<(), AccessError>;
+    async fn get_media_variants(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+    ) -> Result<Vec<model::MediaVariant>, AccessError>;
+    async fn get_media_variant(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+        size: model::VariantSize,
+    ) -> Result<Option<model::MediaVariant>, AccessError>;
+}
+
+#[async_trait]
+impl AccessMedia for MemoryDb {
+    /// Creates a row in the `media` table for a blob that has already been stored.
+    async fn create_media(
+        &self,
+        user_id: u32,
+        media_uuid: Uuid,
+        content_type: String,
+        size: u64,
+        storage_key: String,
+        sha256: String,
+        keep_metadata: bool,
+    ) -> Result<model::Media, AccessError> {
+        let mut conn = self.conn().await?;
+        let media_id = sqlx::query!(
+            "INSERT INTO media (uuid, content_type, size, storage_key, sha256, keep_metadata, user_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            media_uuid.to_string(),
+            content_type,
+            size,
+            storage_key,
+            sha256,
+            keep_metadata,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_id();
+
+        let media = sqlx::query_as!(schema::Media, "SELECT * FROM media WHERE id = ?", media_id)
+            .fetch_one(&mut *conn)
+            .await?
+            .try_into()?;
+
+        Ok(media)
+    }
+
+    /// Records what was read from the media's EXIF, and where its stripped copy is stored.
+    async fn update_media_metadata(
+        &self,
+        media_id: u32,
+        metadata: &model::MediaMetadata,
+        stripped: Option<(String, u64)>,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        let (stripped_storage_key, stripped_size) = stripped.unzip();
+        sqlx::query!(
+            "UPDATE media SET captured_at = ?, latitude = ?, longitude = ?, orientation = ?, camera_make = ?, camera_model = ?, stripped_storage_key = ?, stripped_size = ? WHERE id = ?",
+            metadata.captured_at,
+            metadata.latitude,
+            metadata.longitude,
+            metadata.orientation,
+            metadata.camera_make,
+            metadata.camera_model,
+            stripped_storage_key,
+            stripped_size,
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records the duration, codec and dimensions read from audio or video.
+    async fn update_media_probe(
+        &self,
+        media_id: u32,
+        probe: &model::MediaProbe,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE media SET duration_ms = ?, codec = ?, width = ?, height = ? WHERE id = ?",
+            probe.duration_ms,
+            probe.codec,
+            probe.width,
+            probe.height,
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records where the frame extracted from a video is stored.
+    async fn update_media_poster(
+        &self,
+        media_id: u32,
+        storage_key: String,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE media SET poster_storage_key = ? WHERE id = ?",
+            storage_key,
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the media if it was uploaded by the user.
+    async fn get_media_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+    ) -> Result<model::Media, AccessError> {
+        let mut conn = self.conn().await?;
+        let media = sqlx::query_as!(
+            schema::Media,
+            "SELECT * FROM media WHERE uuid = ? AND user_id = ?",
+            media_uuid.to_string(),
+            user.id()?
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(media)
+    }
+
+    /// Returns every user's images without variants and video without a poster.
+    async fn get_unprocessed_media(&self) -> Result<Vec<model::Media>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::Media,
+            "SELECT * FROM media WHERE (content_type LIKE 'video/%' AND poster_storage_key IS NULL AND poster_failed_at IS NULL) OR (content_type LIKE 'image/%' AND NOT EXISTS (SELECT 1 FROM media_variants WHERE media_variants.media_id = media.id))"
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut media = Vec::with_capacity(rows.len());
+        for m in rows {
+            media.push(m.try_into()?);
+        }
+
+        Ok(media)
+    }
+
+    /// Returns the ids of every user's media that no content has referred to since `updated_before`.
+    /// Media referred to elsewhere, e.g. as a video's poster or from a revision, is included,
+    /// see [AccessMedia::delete_unreferenced_media].
+    async fn get_unreferenced_media_ids(
+        &self,
+        updated_before: DateTime<Utc>,
+    ) -> Result<Vec<u32>, AccessError> {
+        let mut conn = self.conn().await?;
+        let media_ids = sqlx::query_scalar!(
+            "SELECT id FROM media WHERE ref_count = 0 AND updated_at < ?",
+            updated_before
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(media_ids)
+    }
+
+    /// Marks the media as updated, holding off its collection while nothing refers to it.
+    async fn touch_media(&self, media_id: u32) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE media SET updated_at = CURRENT_TIMESTAMP WHERE id = ?",
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records that no poster could be extracted from the video, so it isn't queued again.
+    async fn mark_media_poster_failed(&self, media_id: u32) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE media SET poster_failed_at = CURRENT_TIMESTAMP WHERE id = ?",
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the bytes taken up by the originals of the user's media.
+    async fn get_stored_media_size(&self, user_id: u32) -> Result<u64, AccessError> {
+        let mut conn = self.conn().await?;
+        let size = sqlx::query_scalar!(
+            r#"SELECT CAST(COALESCE(SUM(size), 0) AS UNSIGNED) AS "size!: u64" FROM media WHERE user_id = ?"#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(size)
+    }
+
+    /// Returns the user's media with the given hash, if they've uploaded the file before.
+    async fn get_media_by_sha256(
+        &self,
+        user_id: u32,
+        sha256: &str,
+    ) -> Result<Option<model::Media>, AccessError> {
+        let mut conn = self.conn().await?;
+        let row = sqlx::query_as!(
+            schema::Media,
+            "SELECT * FROM media WHERE user_id = ? AND sha256 = ?",
+            user_id,
+            sha256
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match row {
+            Some(m) => Ok(Some(m.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes the media and its variants once no content refers to it,
+    /// returning the storage keys of the blobs that can then be removed.
+    /// Media still mentioned by one of the owner's revisions is kept so the revision can be restored,
+    /// as is media only mentioned by content without being its `media_id`, like a video's poster.
+    /// Returns nothing if the media is still referenced.
+    async fn delete_unreferenced_media(&self, media_id: u32) -> Result<Vec<String>, AccessError> {
+        let mut conn = self.conn().await?;
+        let media: model::Media = match sqlx::query_as!(
+            schema::Media,
+            "SELECT * FROM media WHERE id = ? AND ref_count = 0 AND NOT EXISTS (SELECT 1 FROM story_revisions JOIN stories ON story_revisions.story_id = stories.id WHERE stories.user_id = media.user_id AND JSON_SEARCH(story_revisions.content, 'one', media.uuid) IS NOT NULL) AND NOT EXISTS (SELECT 1 FROM content JOIN stories ON content.story_id = stories.id WHERE stories.user_id = media.user_id AND JSON_SEARCH(content.details, 'one', media.uuid) IS NOT NULL) FOR UPDATE",
+            media_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        {
+            Some(m) => m.try_into()?,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut storage_keys = sqlx::query_scalar!(
+            "SELECT storage_key FROM media_variants WHERE media_id = ?",
+            media_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sqlx::query!("DELETE FROM media_variants WHERE media_id = ?", media_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM media WHERE id = ?", media_id)
+            .execute(&mut *conn)
+            .await?;
+
+        storage_keys.push(media.storage_key);
+        storage_keys.extend(media.stripped.map(|(storage_key, _)| storage_key));
+        storage_keys.extend(media.poster_storage_key);
+
+        Ok(storage_keys)
+    }
+
+    /// Records a resized copy of the media, replacing any previous copy of the same size.
+    async fn create_media_variant(
+        &self,
+        media_id: u32,
+        size: model::VariantSize,
+        width: u32,
+        height: u32,
+        content_type: String,
+        byte_size: u64,
+        storage_key: String,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "REPLACE INTO media_variants (size, width, height, content_type, byte_size, storage_key, media_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            size.as_str(),
+            width,
+            height,
+            content_type,
+            byte_size,
+            storage_key,
+            media_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the variants generated so far for the user's media, smallest first.
+    async fn get_media_variants(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+    ) -> Result<Vec<model::MediaVariant>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::MediaVariant,
+            "SELECT media_variants.* FROM media_variants JOIN media ON media_variants.media_id = media.id WHERE media.uuid = ? AND media.user_id = ? ORDER BY media_variants.width",
+            media_uuid.to_string(),
+            user.id()?
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut variants = Vec::new();
+        for v in rows.into_iter() {
+            variants.push(v.try_into()?);
+        }
+
+        Ok(variants)
+    }
+
+    /// Returns a variant of the user's media, if it has been generated.
+    async fn get_media_variant(
+        &self,
+        user: &VerifiedUser,
+        media_uuid: Uuid,
+        size: model::VariantSize,
+    ) -> Result<Option<model::MediaVariant>, AccessError> {
+        let mut conn = self.conn().await?;
+        let row = sqlx::query_as!(
+            schema::MediaVariant,
+            "SELECT media_variants.* FROM media_variants JOIN media ON media_variants.media_id = media.id WHERE media.uuid = ? AND media.user_id = ? AND media_variants.size = ?",
+            media_uuid.to_string(),
+            user.id()?,
+            size.as_str()
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match row {
+            Some(v) => Ok(Some(v.try_into()?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,10 +1,24 @@
-use sqlx::MySqlPool;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use sqlx::{pool::PoolConnection, MySql, MySqlConnection, MySqlPool, Transaction};
+use tokio::sync::{Mutex, MutexGuard};
 
 use crate::{api, AppError};
 
+pub mod api_token;
+pub mod credential;
+pub mod media;
 pub mod prompts;
+pub mod recovery;
+pub mod revision;
 mod schema;
+pub mod session;
 pub mod story;
+pub mod upload;
 pub mod user;
 
 #[derive(Debug)]
@@ -13,6 +27,10 @@ pub enum AccessError {
     Api(api::ApiError),
     Schema(schema::SchemaError),
     App(AppError),
+    /// `commit` was called while another handle to the transaction was still alive.
+    TransactionInUse,
+    /// The row was updated by someone else since it was read.
+    StaleVersion,
 }
 
 impl From<AppError> for AccessError {
@@ -42,10 +60,96 @@ impl From<api::ApiError> for AccessError {
 #[derive(Clone)]
 pub struct MemoryDb {
     inner: MySqlPool,
+    tx: Option<Arc<Mutex<Transaction<'static, MySql>>>>,
+    /// Only the handle that began a transaction commits it, see [Transactional].
+    owns_tx: bool,
 }
 
 impl MemoryDb {
     pub fn new(inner: MySqlPool) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            tx: None,
+            owns_tx: false,
+        }
+    }
+
+    /// Returns a connection to run queries on.
+    /// Inside a transaction this is the transaction's connection, otherwise one from the pool.
+    async fn conn(&self) -> Result<Conn<'_>, AccessError> {
+        match &self.tx {
+            Some(tx) => Ok(Conn::Tx(tx.lock().await)),
+            None => Ok(Conn::Pool(self.inner.acquire().await?)),
+        }
+    }
+}
+
+/// A connection borrowed for the duration of a query, either from the pool or a transaction.
+enum Conn<'a> {
+    Pool(PoolConnection<MySql>),
+    Tx(MutexGuard<'a, Transaction<'static, MySql>>),
+}
+
+impl Deref for Conn<'_> {
+    type Target = MySqlConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Conn::Pool(conn) => conn,
+            Conn::Tx(tx) => tx,
+        }
+    }
+}
+
+impl DerefMut for Conn<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Conn::Pool(conn) => conn,
+            Conn::Tx(tx) => tx,
+        }
+    }
+}
+
+/// A unit of work spanning several access calls.
+///
+/// `begin` returns a handle whose queries all run in one transaction, which is only applied
+/// once `commit` is called. Dropping the handle without committing rolls everything back.
+#[async_trait]
+pub trait Transactional: Sized {
+    async fn begin(&self) -> Result<Self, AccessError>;
+    async fn commit(self) -> Result<(), AccessError>;
+}
+
+#[async_trait]
+impl Transactional for MemoryDb {
+    /// Begins a transaction. If one is already in progress, the returned handle joins it
+    /// and leaves committing to the handle that began it.
+    async fn begin(&self) -> Result<Self, AccessError> {
+        if self.tx.is_some() {
+            return Ok(Self {
+                owns_tx: false,
+                ..self.clone()
+            });
+        }
+
+        let tx = self.inner.begin().await?;
+
+        Ok(Self {
+            inner: self.inner.clone(),
+            tx: Some(Arc::new(Mutex::new(tx))),
+            owns_tx: true,
+        })
+    }
+
+    async fn commit(self) -> Result<(), AccessError> {
+        let tx = match self.tx {
+            Some(tx) if self.owns_tx => tx,
+            _ => return Ok(()),
+        };
+
+        let tx = Arc::try_unwrap(tx).map_err(|_| AccessError::TransactionInUse)?;
+        tx.into_inner().commit().await?;
+
+        Ok(())
     }
 }
@@ -12,8 +12,9 @@ pub trait AccessPrompt {
 #[async_trait]
 impl AccessPrompt for MemoryDb {
     async fn get_prompts(&self) -> Result<Vec<model::Prompt>, AccessError> {
+        let mut conn = self.conn().await?;
         let rows = sqlx::query_as!(schema::Prompt, "SELECT * FROM prompts")
-            .fetch_all(&self.inner)
+            .fetch_all(&mut *conn)
             .await?;
 
         let mut prompts = Vec::new();
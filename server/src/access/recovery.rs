@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+
+use crate::model;
+
+use super::{schema, AccessError, MemoryDb, Transactional};
+
+#[async_trait]
+pub trait AccessRecoveryCode {
+    async fn replace_recovery_codes(
+        &self,
+        user_id: u32,
+        code_hashes: Vec<String>,
+    ) -> Result<(), AccessError>;
+    async fn get_unused_recovery_codes(
+        &self,
+        user_id: u32,
+    ) -> Result<Vec<model::RecoveryCode>, AccessError>;
+    async fn use_recovery_code(&self, code_id: u32) -> Result<bool, AccessError>;
+}
+
+#[async_trait]
+impl AccessRecoveryCode for MemoryDb {
+    /// Deletes all of the user's recovery codes, used or not, and stores the new set of hashes.
+    /// Runs in its own transaction, or joins the one already in progress.
+    async fn replace_recovery_codes(
+        &self,
+        user_id: u32,
+        code_hashes: Vec<String>,
+    ) -> Result<(), AccessError> {
+        let tx = self.begin().await?;
+        {
+            let mut conn = tx.conn().await?;
+
+            sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+                .execute(&mut *conn)
+                .await?;
+
+            for code_hash in code_hashes {
+                sqlx::query!(
+                    "INSERT INTO recovery_codes (code_hash, user_id) VALUES (?, ?)",
+                    code_hash,
+                    user_id
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Returns the user's recovery codes that haven't been redeemed yet.
+    async fn get_unused_recovery_codes(
+        &self,
+        user_id: u32,
+    ) -> Result<Vec<model::RecoveryCode>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::RecoveryCode,
+            "SELECT * FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(rows.into_iter().map(|c| c.into()).collect())
+    }
+
+    /// Marks a recovery code as redeemed.
+    ///
+    /// Returns false if the code was already redeemed, e.g. by a concurrent request.
+    async fn use_recovery_code(&self, code_id: u32) -> Result<bool, AccessError> {
+        let mut conn = self.conn().await?;
+        let updated = sqlx::query!(
+            "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP WHERE id = ? AND used_at IS NULL",
+            code_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+        Ok(updated > 0)
+    }
+}
@@ -0,0 +1,124 @@
+use async_trait::async_trait;
+
+use crate::model;
+
+use super::{schema, AccessError, MemoryDb};
+
+#[async_trait]
+pub trait AccessRevision {
+    async fn create_revision(
+        &self,
+        story: &model::Story,
+        content: &[model::Content],
+    ) -> Result<(), AccessError>;
+    async fn get_story_revisions(&self, story_id: u32)
+        -> Result<Vec<model::Revision>, AccessError>;
+    async fn get_revision(
+        &self,
+        story_id: u32,
+        version: u32,
+    ) -> Result<model::Revision, AccessError>;
+    async fn get_previous_revision(
+        &self,
+        story_id: u32,
+        version: u32,
+    ) -> Result<Option<model::Revision>, AccessError>;
+}
+
+#[async_trait]
+impl AccessRevision for MemoryDb {
+    /// Records a snapshot of the story's title, location and content at its current version.
+    /// The location is read from `story_locations`, so it should be set first.
+    async fn create_revision(
+        &self,
+        story: &model::Story,
+        content: &[model::Content],
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        let snapshot: Vec<model::RevisionContent> = content
+            .iter()
+            .map(|c| model::RevisionContent {
+                uuid: c.uuid,
+                details: c.details.clone(),
+            })
+            .collect();
+        let snapshot = serde_json::to_value(snapshot).map_err(schema::SchemaError::from)?;
+
+        sqlx::query!(
+            "INSERT INTO story_revisions (version, title, content, location, story_id) VALUES (?, ?, ?, (SELECT JSON_OBJECT('latitude', ST_Latitude(position), 'longitude', ST_Longitude(position), 'place_name', place_name) FROM story_locations WHERE story_id = ?), ?)",
+            story.version,
+            story.title,
+            snapshot,
+            story.id,
+            story.id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns every revision of the story, newest first.
+    async fn get_story_revisions(
+        &self,
+        story_id: u32,
+    ) -> Result<Vec<model::Revision>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::Revision,
+            "SELECT * FROM story_revisions WHERE story_id = ? ORDER BY version DESC",
+            story_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut revisions = Vec::new();
+        for r in rows.into_iter() {
+            revisions.push(r.try_into()?);
+        }
+
+        Ok(revisions)
+    }
+
+    async fn get_revision(
+        &self,
+        story_id: u32,
+        version: u32,
+    ) -> Result<model::Revision, AccessError> {
+        let mut conn = self.conn().await?;
+        let revision = sqlx::query_as!(
+            schema::Revision,
+            "SELECT * FROM story_revisions WHERE story_id = ? AND version = ?",
+            story_id,
+            version
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(revision)
+    }
+
+    /// Returns the latest revision recorded before `version`.
+    /// Versions aren't contiguous, moving a story to the trash bumps its version without a revision.
+    async fn get_previous_revision(
+        &self,
+        story_id: u32,
+        version: u32,
+    ) -> Result<Option<model::Revision>, AccessError> {
+        let mut conn = self.conn().await?;
+        let row = sqlx::query_as!(
+            schema::Revision,
+            "SELECT * FROM story_revisions WHERE story_id = ? AND version < ? ORDER BY version DESC LIMIT 1",
+            story_id,
+            version
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match row {
+            Some(r) => Ok(Some(r.try_into()?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::str::FromStr;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -14,18 +14,20 @@ pub enum SchemaError {
     ParseUuid,
     ParseDeleted,
     ParseJson,
+    ParseScope,
+    ParseVariantSize,
 }
 
 impl From<uuid::Error> for SchemaError {
     fn from(err: uuid::Error) -> Self {
-        println!("{:?}", err);
+        tracing::error!(error = ?err, "failed to parse uuid");
         Self::ParseUuid
     }
 }
 
 impl From<serde_json::Error> for SchemaError {
     fn from(err: serde_json::Error) -> Self {
-        println!("{:?}", err);
+        tracing::error!(error = ?err, "failed to parse json");
         Self::ParseJson
     }
 }
@@ -62,6 +64,8 @@ pub struct Story {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub deleted: i8,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub version: u32,
 }
 
 impl TryFrom<Story> for model::Story {
@@ -80,6 +84,94 @@ impl TryFrom<Story> for model::Story {
                 1 => true,
                 _ => return Err(SchemaError::ParseDeleted),
             },
+            deleted_at: s.deleted_at,
+            version: s.version,
+        })
+    }
+}
+
+pub struct StoryLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: Option<String>,
+}
+
+impl From<StoryLocation> for model::StoryLocation {
+    fn from(l: StoryLocation) -> Self {
+        model::StoryLocation {
+            latitude: l.latitude,
+            longitude: l.longitude,
+            place_name: l.place_name,
+        }
+    }
+}
+
+pub struct StoryPin {
+    pub uuid: String,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: Option<String>,
+}
+
+impl TryFrom<StoryPin> for model::StoryPin {
+    type Error = SchemaError;
+
+    fn try_from(p: StoryPin) -> Result<Self, Self::Error> {
+        Ok(model::StoryPin {
+            uuid: Uuid::from_str(&p.uuid)?,
+            title: p.title,
+            location: model::StoryLocation {
+                latitude: p.latitude,
+                longitude: p.longitude,
+                place_name: p.place_name,
+            },
+            created_at: p.created_at,
+        })
+    }
+}
+
+pub struct StoryCell {
+    pub count: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+    pub uuid: String,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+    pub place_name: Option<String>,
+}
+
+impl TryFrom<StoryCell> for model::StoryCell {
+    type Error = SchemaError;
+
+    fn try_from(c: StoryCell) -> Result<Self, Self::Error> {
+        let location = model::StoryLocation {
+            latitude: c.latitude,
+            longitude: c.longitude,
+            place_name: c.place_name,
+        };
+
+        Ok(model::StoryCell {
+            count: c.count as u32,
+            latitude: c.latitude,
+            longitude: c.longitude,
+            bbox: model::BoundingBox {
+                west: c.west,
+                south: c.south,
+                east: c.east,
+                north: c.north,
+            },
+            story: model::StoryPin {
+                uuid: Uuid::from_str(&c.uuid)?,
+                title: c.title,
+                location,
+                created_at: c.created_at,
+            },
         })
     }
 }
@@ -93,6 +185,8 @@ pub struct Content {
     pub details: sqlx::types::JsonValue,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub position: u32,
+    pub media_id: Option<u32>,
 }
 
 impl TryFrom<Content> for model::Content {
@@ -105,12 +199,279 @@ impl TryFrom<Content> for model::Content {
             uuid: Uuid::from_str(&c.uuid)?,
             kind: c.kind,
             details: serde_json::from_value(c.details)?,
+            position: c.position,
+            media_id: c.media_id,
+            created_at: c.created_at,
+            updated_at: c.updated_at,
+        })
+    }
+}
+
+pub struct Credential {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: String,
+    pub credential_id: Vec<u8>,
+    pub passkey: sqlx::types::JsonValue,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub name: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<Credential> for model::Credential {
+    type Error = SchemaError;
+
+    fn try_from(c: Credential) -> Result<Self, Self::Error> {
+        Ok(model::Credential {
+            id: c.id,
+            user_id: c.user_id,
+            uuid: Uuid::from_str(&c.uuid)?,
+            name: c.name,
+            passkey: serde_json::from_value(c.passkey)?,
+            last_used_at: c.last_used_at,
             created_at: c.created_at,
             updated_at: c.updated_at,
         })
     }
 }
 
+pub struct Session {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: String,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub device_label: Option<String>,
+    pub ip: Option<String>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+impl TryFrom<Session> for model::Session {
+    type Error = SchemaError;
+
+    fn try_from(s: Session) -> Result<Self, Self::Error> {
+        Ok(model::Session {
+            id: s.id,
+            user_id: s.user_id,
+            uuid: Uuid::from_str(&s.uuid)?,
+            device_label: s.device_label,
+            ip: s.ip,
+            last_seen_at: s.last_seen_at,
+            expires_at: s.expires_at,
+            created_at: s.created_at,
+            updated_at: s.updated_at,
+        })
+    }
+}
+
+pub struct Upload {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: String,
+    pub content_type: String,
+    pub size: u64,
+    pub received: u64,
+    pub sha256: String,
+    pub keep_metadata: i8,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<Upload> for model::Upload {
+    type Error = SchemaError;
+
+    fn try_from(u: Upload) -> Result<Self, Self::Error> {
+        Ok(model::Upload {
+            id: u.id,
+            user_id: u.user_id,
+            uuid: Uuid::from_str(&u.uuid)?,
+            content_type: u.content_type,
+            size: u.size,
+            received: u.received,
+            sha256: u.sha256,
+            keep_metadata: u.keep_metadata != 0,
+            expires_at: u.expires_at,
+            created_at: u.created_at,
+            updated_at: u.updated_at,
+        })
+    }
+}
+
+pub struct RecoveryCode {
+    pub id: u32,
+    pub user_id: u32,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<RecoveryCode> for model::RecoveryCode {
+    fn from(c: RecoveryCode) -> Self {
+        model::RecoveryCode {
+            id: c.id,
+            user_id: c.user_id,
+            code_hash: c.code_hash,
+            used_at: c.used_at,
+            created_at: c.created_at,
+        }
+    }
+}
+
+pub struct ApiToken {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: String,
+    pub name: String,
+    pub token_hash: String,
+    pub scope: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<ApiToken> for model::ApiToken {
+    type Error = SchemaError;
+
+    fn try_from(t: ApiToken) -> Result<Self, Self::Error> {
+        Ok(model::ApiToken {
+            id: t.id,
+            user_id: t.user_id,
+            uuid: Uuid::from_str(&t.uuid)?,
+            name: t.name,
+            scope: model::Scope::from_str(&t.scope).map_err(|_| SchemaError::ParseScope)?,
+            last_used_at: t.last_used_at,
+            created_at: t.created_at,
+            updated_at: t.updated_at,
+        })
+    }
+}
+
+pub struct Media {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: String,
+    pub content_type: String,
+    pub size: u64,
+    pub storage_key: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub orientation: Option<u16>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub stripped_storage_key: Option<String>,
+    pub stripped_size: Option<u64>,
+    pub keep_metadata: i8,
+    pub sha256: Option<String>,
+    pub ref_count: u32,
+    pub duration_ms: Option<u64>,
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub poster_storage_key: Option<String>,
+    pub poster_failed_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<Media> for model::Media {
+    type Error = SchemaError;
+
+    fn try_from(m: Media) -> Result<Self, Self::Error> {
+        Ok(model::Media {
+            id: m.id,
+            user_id: m.user_id,
+            uuid: Uuid::from_str(&m.uuid)?,
+            content_type: m.content_type,
+            size: m.size,
+            storage_key: m.storage_key,
+            metadata: model::MediaMetadata {
+                captured_at: m.captured_at,
+                latitude: m.latitude,
+                longitude: m.longitude,
+                orientation: m.orientation,
+                camera_make: m.camera_make,
+                camera_model: m.camera_model,
+            },
+            probe: model::MediaProbe {
+                duration_ms: m.duration_ms,
+                codec: m.codec,
+                width: m.width,
+                height: m.height,
+            },
+            poster_storage_key: m.poster_storage_key,
+            poster_failed_at: m.poster_failed_at,
+            stripped: m.stripped_storage_key.zip(m.stripped_size),
+            keep_metadata: m.keep_metadata != 0,
+            sha256: m.sha256,
+            ref_count: m.ref_count,
+            created_at: m.created_at,
+            updated_at: m.updated_at,
+        })
+    }
+}
+
+pub struct MediaVariant {
+    pub id: u32,
+    pub media_id: u32,
+    pub size: String,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+    pub byte_size: u64,
+    pub storage_key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TryFrom<MediaVariant> for model::MediaVariant {
+    type Error = SchemaError;
+
+    fn try_from(v: MediaVariant) -> Result<Self, Self::Error> {
+        Ok(model::MediaVariant {
+            id: v.id,
+            media_id: v.media_id,
+            size: model::VariantSize::from_str(&v.size)
+                .map_err(|_| SchemaError::ParseVariantSize)?,
+            width: v.width,
+            height: v.height,
+            content_type: v.content_type,
+            byte_size: v.byte_size,
+            storage_key: v.storage_key,
+            created_at: v.created_at,
+        })
+    }
+}
+
+pub struct Revision {
+    pub id: u32,
+    pub story_id: u32,
+    pub version: u32,
+    pub title: String,
+    pub content: sqlx::types::JsonValue,
+    pub location: Option<sqlx::types::JsonValue>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl TryFrom<Revision> for model::Revision {
+    type Error = SchemaError;
+
+    fn try_from(r: Revision) -> Result<Self, Self::Error> {
+        Ok(model::Revision {
+            id: r.id,
+            story_id: r.story_id,
+            version: r.version,
+            title: r.title,
+            content: serde_json::from_value(r.content)?,
+            location: r.location.map(serde_json::from_value).transpose()?,
+            created_at: r.created_at,
+        })
+    }
+}
+
 pub struct Prompt {
     pub id: u32,
     pub uuid: String,
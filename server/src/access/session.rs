@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::model;
+
+use super::{schema, AccessError, MemoryDb};
+
+#[async_trait]
+pub trait AccessSession {
+    async fn create_session(
+        &self,
+        user_id: u32,
+        token_hash: String,
+        device_label: Option<String>,
+        ip: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<model::Session, AccessError>;
+    async fn get_session_by_token(
+        &self,
+        token_hash: String,
+    ) -> Result<Option<model::Session>, AccessError>;
+    async fn get_user_sessions(&self, user_id: u32) -> Result<Vec<model::Session>, AccessError>;
+    async fn touch_session(
+        &self,
+        session_id: u32,
+        ip: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AccessError>;
+    async fn delete_session(&self, user_id: u32, session_uuid: Uuid) -> Result<bool, AccessError>;
+}
+
+#[async_trait]
+impl AccessSession for MemoryDb {
+    /// Creates a row in the `sessions` table.
+    /// Only the hash of the session token is stored, the token itself is handed to the client.
+    async fn create_session(
+        &self,
+        user_id: u32,
+        token_hash: String,
+        device_label: Option<String>,
+        ip: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<model::Session, AccessError> {
+        let mut conn = self.conn().await?;
+        let session_uuid = Uuid::new_v4();
+        let session_id = sqlx::query!(
+            "INSERT INTO sessions (uuid, token_hash, device_label, ip, expires_at, user_id) VALUES (?, ?, ?, ?, ?, ?)",
+            session_uuid.to_string(),
+            token_hash,
+            device_label,
+            ip,
+            expires_at,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_id();
+
+        let session = sqlx::query_as!(
+            schema::Session,
+            "SELECT * FROM sessions WHERE id = ?",
+            session_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(session)
+    }
+
+    /// Returns the unexpired session matching the provided token hash, if there is one.
+    async fn get_session_by_token(
+        &self,
+        token_hash: String,
+    ) -> Result<Option<model::Session>, AccessError> {
+        let mut conn = self.conn().await?;
+        let session = sqlx::query_as!(
+            schema::Session,
+            "SELECT * FROM sessions WHERE token_hash = ? AND expires_at > CURRENT_TIMESTAMP",
+            token_hash
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match session {
+            Some(session) => Ok(Some(session.try_into()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the user's unexpired sessions, most recently used first.
+    async fn get_user_sessions(&self, user_id: u32) -> Result<Vec<model::Session>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::Session,
+            "SELECT * FROM sessions WHERE user_id = ? AND expires_at > CURRENT_TIMESTAMP ORDER BY last_seen_at DESC",
+            user_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut sessions = Vec::new();
+        for s in rows.into_iter() {
+            sessions.push(s.try_into()?);
+        }
+
+        Ok(sessions)
+    }
+
+    /// Records activity on a session and slides its expiry forward.
+    async fn touch_session(
+        &self,
+        session_id: u32,
+        ip: Option<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        sqlx::query!(
+            "UPDATE sessions SET last_seen_at = CURRENT_TIMESTAMP, ip = COALESCE(?, ip), expires_at = ? WHERE id = ?",
+            ip,
+            expires_at,
+            session_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes one of the user's sessions, immediately invalidating its token.
+    ///
+    /// Returns false if the user has no session with the provided uuid.
+    async fn delete_session(&self, user_id: u32, session_uuid: Uuid) -> Result<bool, AccessError> {
+        let mut conn = self.conn().await?;
+        let deleted = sqlx::query!(
+            "DELETE FROM sessions WHERE uuid = ? AND user_id = ?",
+            session_uuid.to_string(),
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+        Ok(deleted > 0)
+    }
+}
@@ -1,4 +1,6 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::MySqlConnection;
 use uuid::Uuid;
 
 use crate::{api, auth::VerifiedUser, model};
@@ -15,7 +17,9 @@ pub trait AccessStory {
     ) -> Result<model::Story, AccessError>;
     async fn create_content(
         &self,
+        user: &VerifiedUser,
         story_id: u32,
+        first_position: u32,
         content: Vec<api::ContentDetails>,
     ) -> Result<Vec<model::Content>, AccessError>;
     async fn get_story_by_uuid(
@@ -23,8 +27,31 @@ pub trait AccessStory {
         user: &VerifiedUser,
         story_uuid: Uuid,
     ) -> Result<model::Story, AccessError>;
-    async fn get_content_by_uuid(&self, content_uuid: Uuid) -> Result<model::Content, AccessError>;
-    async fn get_story_content(&self, story_id: u32) -> Result<Vec<model::Content>, AccessError>;
+    async fn get_deleted_story_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        story_uuid: Uuid,
+    ) -> Result<model::Story, AccessError>;
+    async fn list_stories(
+        &self,
+        user: &VerifiedUser,
+        before: Option<(DateTime<Utc>, u32)>,
+        limit: u32,
+    ) -> Result<Vec<model::Story>, AccessError>;
+    async fn list_deleted_stories(
+        &self,
+        user: &VerifiedUser,
+    ) -> Result<Vec<model::Story>, AccessError>;
+    async fn get_content_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        content_uuid: Uuid,
+    ) -> Result<model::Content, AccessError>;
+    async fn get_story_content(
+        &self,
+        user: &VerifiedUser,
+        story_id: u32,
+    ) -> Result<Vec<model::Content>, AccessError>;
     async fn update_story(
         &self,
         user: &VerifiedUser,
@@ -32,9 +59,45 @@ pub trait AccessStory {
     ) -> Result<model::Story, AccessError>;
     async fn update_content(
         &self,
+        user: &VerifiedUser,
         content_id: u32,
         content_updates: api::ContentDetails,
     ) -> Result<model::Content, AccessError>;
+    async fn set_content_position(
+        &self,
+        user: &VerifiedUser,
+        content_id: u32,
+        position: u32,
+    ) -> Result<(), AccessError>;
+    async fn delete_content(&self, user: &VerifiedUser, content_id: u32)
+        -> Result<(), AccessError>;
+    async fn get_story_location(
+        &self,
+        story_id: u32,
+    ) -> Result<Option<model::StoryLocation>, AccessError>;
+    async fn set_story_location(
+        &self,
+        story_id: u32,
+        location: Option<model::StoryLocation>,
+    ) -> Result<(), AccessError>;
+    async fn list_story_pins(
+        &self,
+        user: &VerifiedUser,
+        bbox: &model::BoundingBox,
+        limit: u32,
+    ) -> Result<Vec<model::StoryPin>, AccessError>;
+    async fn list_story_cells(
+        &self,
+        user: &VerifiedUser,
+        bbox: &model::BoundingBox,
+        cell_size: f64,
+        limit: u32,
+    ) -> Result<Vec<model::StoryCell>, AccessError>;
+    async fn purge_story(&self, story_id: u32) -> Result<Vec<u32>, AccessError>;
+    async fn get_expired_story_ids(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<Vec<u32>, AccessError>;
 }
 
 #[async_trait]
@@ -46,6 +109,7 @@ impl AccessStory for super::MemoryDb {
         user: &VerifiedUser,
         title: String,
     ) -> Result<model::Story, AccessError> {
+        let mut conn = self.conn().await?;
         let story_uuid = Uuid::new_v4();
         let story_id = sqlx::query!(
             "INSERT INTO stories (uuid, title, deleted, user_id) VALUES (?, ?, ?, ?)",
@@ -54,7 +118,7 @@ impl AccessStory for super::MemoryDb {
             false,
             user.id()?
         )
-        .execute(&self.inner)
+        .execute(&mut *conn)
         .await
         .map_err(AccessError::Sql)?
         .last_insert_id();
@@ -64,94 +128,221 @@ impl AccessStory for super::MemoryDb {
             "SELECT * FROM stories WHERE id = ?",
             story_id
         )
-        .fetch_one(&self.inner)
+        .fetch_one(&mut *conn)
         .await
         .map_err(AccessError::Sql)?;
 
         Ok(story.try_into()?)
     }
 
-    /// Creates a row(s) in the `content` table.
+    /// Creates a row(s) in the `content` table, positioned one after another from `first_position`.
     /// Musted be used with `create_story` to first populate the corresponding `story` row.
+    /// Nothing is inserted unless the story belongs to the user.
+    /// Media the content refers to gains a reference, so this should be used within a transaction.
     async fn create_content(
         &self,
+        user: &VerifiedUser,
         story_id: u32,
+        first_position: u32,
         content: Vec<api::ContentDetails>,
     ) -> Result<Vec<model::Content>, AccessError> {
+        let user_id = user.id()?;
+        let mut conn = self.conn().await?;
         let mut db_content = Vec::new();
-        for c in content {
+        for (offset, c) in content.into_iter().enumerate() {
             let content_uuid = Uuid::new_v4();
             let kind = c.kind();
             let details = c.details()?;
+            let media_uuid = c.media().map(|m| m.to_string());
+            let position = first_position + offset as u32;
 
-            let content_id = sqlx::query!(
-                "INSERT INTO content (uuid, kind, details, story_id) VALUES (?, ?, ?, ?)",
+            let result = sqlx::query!(
+                "INSERT INTO content (uuid, kind, details, position, media_id, story_id) SELECT ?, ?, ?, ?, (SELECT id FROM media WHERE uuid = ? AND user_id = ?), id FROM stories WHERE id = ? AND user_id = ?",
                 content_uuid.to_string(),
                 kind,
                 details,
-                story_id
+                position,
+                media_uuid,
+                user_id,
+                story_id,
+                user_id
             )
-            .execute(&self.inner)
+            .execute(&mut *conn)
             .await
-            .map_err(AccessError::Sql)?
-            .last_insert_id();
+            .map_err(AccessError::Sql)?;
 
-            let c = sqlx::query_as!(
+            if result.rows_affected() == 0 {
+                return Err(AccessError::Sql(sqlx::Error::RowNotFound));
+            }
+            let content_id = result.last_insert_id();
+
+            let c: model::Content = sqlx::query_as!(
                 schema::Content,
                 "SELECT * FROM content WHERE id = ?",
                 content_id
             )
-            .fetch_one(&self.inner)
+            .fetch_one(&mut *conn)
             .await
             .map_err(AccessError::Sql)?
             .try_into()?;
 
+            if let Some(media_id) = c.media_id {
+                sqlx::query!(
+                    "UPDATE media SET ref_count = ref_count + 1 WHERE id = ?",
+                    media_id
+                )
+                .execute(&mut *conn)
+                .await
+                .map_err(AccessError::Sql)?;
+            }
+            set_content_location(&mut *conn, &c).await?;
+
             db_content.push(c);
         }
         Ok(db_content)
     }
 
-    /// Returns one story that matches the provided story_uuid.
+    /// Returns one story that matches the provided story_uuid. Stories in the trash are excluded.
     /// For the story's content, see `get_story_content`
     async fn get_story_by_uuid(
         &self,
         user: &VerifiedUser,
         story_uuid: Uuid,
     ) -> Result<model::Story, AccessError> {
+        let mut conn = self.conn().await?;
+        let story = sqlx::query_as!(
+            schema::Story,
+            "SELECT * FROM stories WHERE user_id = ? AND uuid = ? AND deleted = FALSE",
+            user.id()?,
+            story_uuid.to_string()
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(story)
+    }
+
+    /// Returns one story in the trash that matches the provided story_uuid.
+    async fn get_deleted_story_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        story_uuid: Uuid,
+    ) -> Result<model::Story, AccessError> {
+        let mut conn = self.conn().await?;
         let story = sqlx::query_as!(
             schema::Story,
-            "SELECT * FROM stories WHERE user_id = ? AND uuid = ?",
+            "SELECT * FROM stories WHERE user_id = ? AND uuid = ? AND deleted = TRUE",
             user.id()?,
             story_uuid.to_string()
         )
-        .fetch_one(&self.inner)
+        .fetch_one(&mut *conn)
         .await?
         .try_into()?;
 
         Ok(story)
     }
 
-    async fn get_content_by_uuid(&self, content_uuid: Uuid) -> Result<model::Content, AccessError> {
+    /// Returns the user's stories that haven't been deleted, newest first.
+    /// When `before` is provided, only stories that sort after that `(created_at, id)` are returned.
+    async fn list_stories(
+        &self,
+        user: &VerifiedUser,
+        before: Option<(DateTime<Utc>, u32)>,
+        limit: u32,
+    ) -> Result<Vec<model::Story>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = match before {
+            Some((created_at, id)) => {
+                sqlx::query_as!(
+                    schema::Story,
+                    "SELECT * FROM stories WHERE user_id = ? AND deleted = FALSE AND (created_at < ? OR (created_at = ? AND id < ?)) ORDER BY created_at DESC, id DESC LIMIT ?",
+                    user.id()?,
+                    created_at,
+                    created_at,
+                    id,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+            None => {
+                sqlx::query_as!(
+                    schema::Story,
+                    "SELECT * FROM stories WHERE user_id = ? AND deleted = FALSE ORDER BY created_at DESC, id DESC LIMIT ?",
+                    user.id()?,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+        };
+
+        let mut stories = Vec::new();
+        for s in rows.into_iter() {
+            stories.push(s.try_into()?);
+        }
+
+        Ok(stories)
+    }
+
+    /// Returns the user's stories in the trash, most recently deleted first.
+    async fn list_deleted_stories(
+        &self,
+        user: &VerifiedUser,
+    ) -> Result<Vec<model::Story>, AccessError> {
+        let mut conn = self.conn().await?;
+        let rows = sqlx::query_as!(
+            schema::Story,
+            "SELECT * FROM stories WHERE user_id = ? AND deleted = TRUE ORDER BY deleted_at DESC, id DESC",
+            user.id()?
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut stories = Vec::new();
+        for s in rows.into_iter() {
+            stories.push(s.try_into()?);
+        }
+
+        Ok(stories)
+    }
+
+    /// Returns a content row, provided the story it belongs to is the user's.
+    async fn get_content_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        content_uuid: Uuid,
+    ) -> Result<model::Content, AccessError> {
+        let mut conn = self.conn().await?;
         let content = sqlx::query_as!(
             schema::Content,
-            "SELECT * FROM content WHERE uuid = ?",
-            content_uuid.to_string()
+            "SELECT content.* FROM content JOIN stories ON content.story_id = stories.id WHERE content.uuid = ? AND stories.user_id = ?",
+            content_uuid.to_string(),
+            user.id()?
         )
-        .fetch_one(&self.inner)
+        .fetch_one(&mut *conn)
         .await?
         .try_into()?;
 
         Ok(content)
     }
 
-    /// Returns the specified story's content.
-    async fn get_story_content(&self, story_id: u32) -> Result<Vec<model::Content>, AccessError> {
+    /// Returns the specified story's content, in order.
+    /// Empty if the story isn't the user's.
+    async fn get_story_content(
+        &self,
+        user: &VerifiedUser,
+        story_id: u32,
+    ) -> Result<Vec<model::Content>, AccessError> {
+        let mut conn = self.conn().await?;
         let rows = sqlx::query_as!(
             schema::Content,
-            "SELECT * FROM content WHERE story_id = ?",
-            story_id
+            "SELECT content.* FROM content JOIN stories ON content.story_id = stories.id WHERE content.story_id = ? AND stories.user_id = ? ORDER BY content.position, content.id",
+            story_id,
+            user.id()?
         )
-        .fetch_all(&self.inner)
+        .fetch_all(&mut *conn)
         .await?;
 
         let mut content = Vec::new();
@@ -164,28 +355,37 @@ impl AccessStory for super::MemoryDb {
 
     /// References the provided story [story_updates] to determine what updates to the row should be made.
     /// Only the row's `title` and `deleted` columns will be updated, if changed.
+    /// `deleted_at` is maintained to match `deleted`.
+    /// The update only applies if the row is still at `story_updates.version`, which is then incremented.
+    /// Returns [AccessError::StaleVersion] otherwise.
     async fn update_story(
         &self,
         user: &VerifiedUser,
         story_updates: model::Story,
     ) -> Result<model::Story, AccessError> {
-        let story_id = sqlx::query!(
-            "UPDATE stories SET title = ?, deleted = ? WHERE id = ? AND user_id = ?",
+        let mut conn = self.conn().await?;
+        let result = sqlx::query!(
+            "UPDATE stories SET title = ?, deleted = ?, deleted_at = IF(?, COALESCE(deleted_at, CURRENT_TIMESTAMP), NULL), version = version + 1 WHERE id = ? AND user_id = ? AND version = ?",
             story_updates.title,
             story_updates.deleted,
+            story_updates.deleted,
             story_updates.id,
-            user.id()?
+            user.id()?,
+            story_updates.version
         )
-        .execute(&self.inner)
-        .await?
-        .last_insert_id();
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AccessError::StaleVersion);
+        }
 
         let story = sqlx::query_as!(
             schema::Story,
             "SELECT * FROM stories WHERE id = ?",
-            story_id
+            story_updates.id
         )
-        .fetch_one(&self.inner)
+        .fetch_one(&mut *conn)
         .await?
         .try_into()?;
 
@@ -194,32 +394,402 @@ impl AccessStory for super::MemoryDb {
 
     /// References the provided content [content_updates] to determine what updates to the row should be made.
     /// Only `kind` and `details` will be updated, if changed.
+    /// The reference moves to whichever media the new details refer to,
+    /// so this should be used within a transaction.
+    /// Fails with `RowNotFound` unless the content belongs to one of the user's stories.
     async fn update_content(
         &self,
+        user: &VerifiedUser,
         content_id: u32,
         content_updates: api::ContentDetails,
     ) -> Result<model::Content, AccessError> {
+        let mut conn = self.conn().await?;
+        let user_id = user.id()?;
         let kind = content_updates.kind();
         let details = content_updates.details()?;
-        let content_id = sqlx::query!(
-            "UPDATE content SET kind = ?, details = ? WHERE id = ?",
+        let media_uuid = content_updates.media().map(|m| m.to_string());
+
+        sqlx::query!(
+            "UPDATE media JOIN content ON content.media_id = media.id JOIN stories ON content.story_id = stories.id SET media.ref_count = media.ref_count - 1 WHERE content.id = ? AND stories.user_id = ?",
+            content_id,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let result = sqlx::query!(
+            "UPDATE content JOIN stories ON content.story_id = stories.id SET content.kind = ?, content.details = ?, content.media_id = (SELECT id FROM media WHERE uuid = ? AND user_id = ?) WHERE content.id = ? AND stories.user_id = ?",
             kind,
             details,
-            content_id
+            media_uuid,
+            user_id,
+            content_id,
+            user_id
         )
-        .execute(&self.inner)
-        .await?
-        .last_insert_id();
+        .execute(&mut *conn)
+        .await?;
 
-        let content = sqlx::query_as!(
+        if result.rows_affected() == 0 {
+            return Err(AccessError::Sql(sqlx::Error::RowNotFound));
+        }
+
+        let content: model::Content = sqlx::query_as!(
             schema::Content,
             "SELECT * FROM content WHERE id = ?",
             content_id
         )
-        .fetch_one(&self.inner)
+        .fetch_one(&mut *conn)
         .await?
         .try_into()?;
 
+        if let Some(media_id) = content.media_id {
+            sqlx::query!(
+                "UPDATE media SET ref_count = ref_count + 1 WHERE id = ?",
+                media_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        set_content_location(&mut *conn, &content).await?;
+
         Ok(content)
     }
+
+    /// Moves a content row to a new position within its story.
+    /// Fails with `RowNotFound` unless the content belongs to one of the user's stories.
+    async fn set_content_position(
+        &self,
+        user: &VerifiedUser,
+        content_id: u32,
+        position: u32,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        let result = sqlx::query!(
+            "UPDATE content JOIN stories ON content.story_id = stories.id SET content.position = ? WHERE content.id = ? AND stories.user_id = ?",
+            position,
+            content_id,
+            user.id()?
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AccessError::Sql(sqlx::Error::RowNotFound));
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a content row from its story.
+    /// Positions of the remaining content aren't changed, see `set_content_position`.
+    /// The media it refers to loses a reference, but is only collected when a story is purged.
+    /// Fails with `RowNotFound` unless the content belongs to one of the user's stories.
+    async fn delete_content(
+        &self,
+        user: &VerifiedUser,
+        content_id: u32,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        let user_id = user.id()?;
+
+        sqlx::query!(
+            "UPDATE media JOIN content ON content.media_id = media.id JOIN stories ON content.story_id = stories.id SET media.ref_count = media.ref_count - 1 WHERE content.id = ? AND stories.user_id = ?",
+            content_id,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query!(
+            "DELETE content_locations FROM content_locations JOIN content ON content_locations.content_id = content.id JOIN stories ON content.story_id = stories.id WHERE content.id = ? AND stories.user_id = ?",
+            content_id,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let result = sqlx::query!(
+            "DELETE content FROM content JOIN stories ON content.story_id = stories.id WHERE content.id = ? AND stories.user_id = ?",
+            content_id,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AccessError::Sql(sqlx::Error::RowNotFound));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the story's location, if it has one.
+    async fn get_story_location(
+        &self,
+        story_id: u32,
+    ) -> Result<Option<model::StoryLocation>, AccessError> {
+        let mut conn = self.conn().await?;
+        let location = sqlx::query_as!(
+            schema::StoryLocation,
+            r#"SELECT ST_Latitude(position) AS "latitude!: f64", ST_Longitude(position) AS "longitude!: f64", place_name FROM story_locations WHERE story_id = ?"#,
+            story_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        Ok(location.map(Into::into))
+    }
+
+    /// Sets the story's location, or removes it when `location` is `None`.
+    /// Doesn't check the story belongs to anyone, it should have been looked up for the user first.
+    async fn set_story_location(
+        &self,
+        story_id: u32,
+        location: Option<model::StoryLocation>,
+    ) -> Result<(), AccessError> {
+        let mut conn = self.conn().await?;
+        match location {
+            Some(location) => {
+                sqlx::query!(
+                    "INSERT INTO story_locations (story_id, position, place_name) VALUES (?, ST_GeomFromText(?, 4326, 'axis-order=long-lat'), ?) AS new ON DUPLICATE KEY UPDATE position = new.position, place_name = new.place_name",
+                    story_id,
+                    point_wkt(location.latitude, location.longitude),
+                    location.place_name
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM story_locations WHERE story_id = ?", story_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the user's stories located within `bbox`, newest first.
+    /// A story without a location of its own is placed at its first location content instead.
+    /// Stories in the trash are excluded.
+    async fn list_story_pins(
+        &self,
+        user: &VerifiedUser,
+        bbox: &model::BoundingBox,
+        limit: u32,
+    ) -> Result<Vec<model::StoryPin>, AccessError> {
+        let mut conn = self.conn().await?;
+        let user_id = user.id()?;
+        let rows = match envelope_wkt(bbox) {
+            Some(envelope) => {
+                sqlx::query_as!(
+                    schema::StoryPin,
+                    r#"SELECT uuid AS "uuid!", title AS "title!", created_at AS "created_at!", latitude AS "latitude!: f64", longitude AS "longitude!: f64", place_name FROM (SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(story_locations.position) AS latitude, ST_Longitude(story_locations.position) AS longitude, story_locations.place_name FROM story_locations JOIN stories ON story_locations.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND MBRContains(ST_GeomFromText(?, 4326, 'axis-order=long-lat'), story_locations.position) UNION ALL SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(content_locations.position), ST_Longitude(content_locations.position), content_locations.place_name FROM content_locations JOIN content ON content_locations.content_id = content.id JOIN stories ON content.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND MBRContains(ST_GeomFromText(?, 4326, 'axis-order=long-lat'), content_locations.position) AND NOT EXISTS (SELECT 1 FROM story_locations WHERE story_locations.story_id = stories.id) AND content.id = (SELECT first_content.id FROM content AS first_content JOIN content_locations AS first_location ON first_location.content_id = first_content.id WHERE first_content.story_id = stories.id ORDER BY first_content.position, first_content.id LIMIT 1)) AS pins WHERE latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ? ORDER BY created_at DESC, id DESC LIMIT ?"#,
+                    user_id,
+                    envelope,
+                    user_id,
+                    envelope,
+                    bbox.south,
+                    bbox.north,
+                    bbox.west,
+                    bbox.east,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+            None => {
+                sqlx::query_as!(
+                    schema::StoryPin,
+                    r#"SELECT uuid AS "uuid!", title AS "title!", created_at AS "created_at!", latitude AS "latitude!: f64", longitude AS "longitude!: f64", place_name FROM (SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(story_locations.position) AS latitude, ST_Longitude(story_locations.position) AS longitude, story_locations.place_name FROM story_locations JOIN stories ON story_locations.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE UNION ALL SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(content_locations.position), ST_Longitude(content_locations.position), content_locations.place_name FROM content_locations JOIN content ON content_locations.content_id = content.id JOIN stories ON content.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND NOT EXISTS (SELECT 1 FROM story_locations WHERE story_locations.story_id = stories.id) AND content.id = (SELECT first_content.id FROM content AS first_content JOIN content_locations AS first_location ON first_location.content_id = first_content.id WHERE first_content.story_id = stories.id ORDER BY first_content.position, first_content.id LIMIT 1)) AS pins WHERE latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ? ORDER BY created_at DESC, id DESC LIMIT ?"#,
+                    user_id,
+                    user_id,
+                    bbox.south,
+                    bbox.north,
+                    bbox.west,
+                    bbox.east,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+        };
+
+        let mut pins = Vec::new();
+        for p in rows.into_iter() {
+            pins.push(p.try_into()?);
+        }
+
+        Ok(pins)
+    }
+
+    /// Groups the user's stories located within `bbox` by the cell of a grid `cell_size` degrees
+    /// across they fall in, placed like in `list_story_pins`. Every story in the box is counted,
+    /// and cells with the newest stories come first.
+    /// Stories in the trash are excluded.
+    async fn list_story_cells(
+        &self,
+        user: &VerifiedUser,
+        bbox: &model::BoundingBox,
+        cell_size: f64,
+        limit: u32,
+    ) -> Result<Vec<model::StoryCell>, AccessError> {
+        let mut conn = self.conn().await?;
+        let user_id = user.id()?;
+        let rows = match envelope_wkt(bbox) {
+            Some(envelope) => {
+                sqlx::query_as!(
+                    schema::StoryCell,
+                    r#"SELECT COUNT(*) AS "count!: i64", AVG(latitude) AS "latitude!: f64", AVG(longitude) AS "longitude!: f64", MIN(longitude) AS "west!: f64", MIN(latitude) AS "south!: f64", MAX(longitude) AS "east!: f64", MAX(latitude) AS "north!: f64", ANY_VALUE(uuid) AS "uuid!: String", ANY_VALUE(title) AS "title!: String", MAX(created_at) AS "created_at!: DateTime<Utc>", ANY_VALUE(place_name) AS "place_name?: String" FROM (SELECT *, FLOOR(longitude / ?) AS cell_x, FLOOR(latitude / ?) AS cell_y FROM (SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(story_locations.position) AS latitude, ST_Longitude(story_locations.position) AS longitude, story_locations.place_name FROM story_locations JOIN stories ON story_locations.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND MBRContains(ST_GeomFromText(?, 4326, 'axis-order=long-lat'), story_locations.position) UNION ALL SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(content_locations.position), ST_Longitude(content_locations.position), content_locations.place_name FROM content_locations JOIN content ON content_locations.content_id = content.id JOIN stories ON content.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND MBRContains(ST_GeomFromText(?, 4326, 'axis-order=long-lat'), content_locations.position) AND NOT EXISTS (SELECT 1 FROM story_locations WHERE story_locations.story_id = stories.id) AND content.id = (SELECT first_content.id FROM content AS first_content JOIN content_locations AS first_location ON first_location.content_id = first_content.id WHERE first_content.story_id = stories.id ORDER BY first_content.position, first_content.id LIMIT 1)) AS located WHERE latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ?) AS pins GROUP BY cell_x, cell_y ORDER BY MAX(created_at) DESC LIMIT ?"#,
+                    cell_size,
+                    cell_size,
+                    user_id,
+                    envelope,
+                    user_id,
+                    envelope,
+                    bbox.south,
+                    bbox.north,
+                    bbox.west,
+                    bbox.east,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+            None => {
+                sqlx::query_as!(
+                    schema::StoryCell,
+                    r#"SELECT COUNT(*) AS "count!: i64", AVG(latitude) AS "latitude!: f64", AVG(longitude) AS "longitude!: f64", MIN(longitude) AS "west!: f64", MIN(latitude) AS "south!: f64", MAX(longitude) AS "east!: f64", MAX(latitude) AS "north!: f64", ANY_VALUE(uuid) AS "uuid!: String", ANY_VALUE(title) AS "title!: String", MAX(created_at) AS "created_at!: DateTime<Utc>", ANY_VALUE(place_name) AS "place_name?: String" FROM (SELECT *, FLOOR(longitude / ?) AS cell_x, FLOOR(latitude / ?) AS cell_y FROM (SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(story_locations.position) AS latitude, ST_Longitude(story_locations.position) AS longitude, story_locations.place_name FROM story_locations JOIN stories ON story_locations.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE UNION ALL SELECT stories.id, stories.uuid, stories.title, stories.created_at, ST_Latitude(content_locations.position), ST_Longitude(content_locations.position), content_locations.place_name FROM content_locations JOIN content ON content_locations.content_id = content.id JOIN stories ON content.story_id = stories.id WHERE stories.user_id = ? AND stories.deleted = FALSE AND NOT EXISTS (SELECT 1 FROM story_locations WHERE story_locations.story_id = stories.id) AND content.id = (SELECT first_content.id FROM content AS first_content JOIN content_locations AS first_location ON first_location.content_id = first_content.id WHERE first_content.story_id = stories.id ORDER BY first_content.position, first_content.id LIMIT 1)) AS located WHERE latitude BETWEEN ? AND ? AND longitude BETWEEN ? AND ?) AS pins GROUP BY cell_x, cell_y ORDER BY MAX(created_at) DESC LIMIT ?"#,
+                    cell_size,
+                    cell_size,
+                    user_id,
+                    user_id,
+                    bbox.south,
+                    bbox.north,
+                    bbox.west,
+                    bbox.east,
+                    limit
+                )
+                .fetch_all(&mut *conn)
+                .await?
+            }
+        };
+
+        let mut cells = Vec::new();
+        for c in rows.into_iter() {
+            cells.push(c.try_into()?);
+        }
+
+        Ok(cells)
+    }
+
+    /// Permanently deletes a story along with all of its content and revisions.
+    /// Should be used within a transaction so the story isn't left half deleted.
+    /// Returns the ids of the media its content referred to, which may now be unreferenced.
+    async fn purge_story(&self, story_id: u32) -> Result<Vec<u32>, AccessError> {
+        let mut conn = self.conn().await?;
+        let media_ids = sqlx::query_scalar!(
+            "SELECT DISTINCT media_id FROM content WHERE story_id = ? AND media_id IS NOT NULL",
+            story_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE media JOIN (SELECT media_id, COUNT(*) AS refs FROM content WHERE story_id = ? GROUP BY media_id) AS story_refs ON story_refs.media_id = media.id SET media.ref_count = media.ref_count - story_refs.refs",
+            story_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        sqlx::query!("DELETE FROM story_revisions WHERE story_id = ?", story_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM story_locations WHERE story_id = ?", story_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!(
+            "DELETE content_locations FROM content_locations JOIN content ON content_locations.content_id = content.id WHERE content.story_id = ?",
+            story_id
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!("DELETE FROM content WHERE story_id = ?", story_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM stories WHERE id = ?", story_id)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(media_ids.into_iter().flatten().collect())
+    }
+
+    /// Returns the ids of every user's stories that were moved to the trash before `deleted_before`.
+    async fn get_expired_story_ids(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<Vec<u32>, AccessError> {
+        let mut conn = self.conn().await?;
+        let story_ids = sqlx::query_scalar!(
+            "SELECT id FROM stories WHERE deleted = TRUE AND deleted_at < ?",
+            deleted_before
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(story_ids)
+    }
+}
+
+/// Copies location content's coordinates into `content_locations`, where the map finds them,
+/// and removes them once the content is something else.
+async fn set_content_location(
+    conn: &mut MySqlConnection,
+    content: &model::Content,
+) -> Result<(), AccessError> {
+    match &content.details {
+        model::ContentDetails::Location(location) => {
+            sqlx::query!(
+                "INSERT INTO content_locations (content_id, position, place_name) VALUES (?, ST_GeomFromText(?, 4326, 'axis-order=long-lat'), ?) AS new ON DUPLICATE KEY UPDATE position = new.position, place_name = new.place_name",
+                content.id,
+                point_wkt(location.latitude, location.longitude),
+                location.place_name
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        _ => {
+            sqlx::query!(
+                "DELETE FROM content_locations WHERE content_id = ?",
+                content.id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A point as WKT, longitude first to match the `axis-order=long-lat` it's parsed with.
+fn point_wkt(latitude: f64, longitude: f64) -> String {
+    format!("POINT({} {})", longitude, latitude)
+}
+
+/// The box as a WKT polygon the spatial index can narrow stories down with.
+/// Edges of a polygon in SRID 4326 follow great circles rather than the box's parallels,
+/// so this only covers boxes within a hemisphere and away from the poles,
+/// where the polygon's bounding rectangle still holds the whole box.
+/// Wider boxes, and ones too thin to make a valid polygon, are matched on coordinates alone.
+fn envelope_wkt(bbox: &model::BoundingBox) -> Option<String> {
+    let within_hemisphere = bbox.width() < 180.0 && bbox.south > -90.0 && bbox.north < 90.0;
+    if !within_hemisphere || bbox.width() <= 0.0 || bbox.south >= bbox.north {
+        return None;
+    }
+
+    let (west, south, east, north) = (bbox.west, bbox.south, bbox.east, bbox.north);
+    Some(format!(
+        "POLYGON(({} {}, {} {}, {} {}, {} {}, {} {}))",
+        west, south, east, south, east, north, west, north, west, south
+    ))
 }
@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{auth::VerifiedUser, model};
+
+use super::{schema, AccessError, MemoryDb};
+
+#[async_trait]
+pub trait AccessUpload {
+    async fn create_upload(
+        &self,
+        user_id: u32,
+        content_type: String,
+        size: u64,
+        sha256: String,
+        keep_metadata: bool,
+        expires_at: DateTime<Utc>,
+    ) -> Result<model::Upload, AccessError>;
+    async fn get_upload_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        upload_uuid: Uuid,
+    ) -> Result<model::Upload, AccessError>;
+    async fn get_pending_upload_size(&self, user_id: u32) -> Result<u64, AccessError>;
+    async fn append_upload_chunk(
+        &self,
+        upload_id: u32,
+        offset: u64,
+        size: u64,
+        storage_key: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, AccessError>;
+    async fn get_upload_chunks(&self, upload_id: u32) -> Result<Vec<String>, AccessError>;
+    async fn delete_upload(&self, upload_id: u32) -> Result<Vec<String>, AccessError>;
+    async fn get_expired_upload_ids(&self) -> Result<Vec<u32>, AccessError>;
+}
+
+#[async_trait]
+impl AccessUpload for MemoryDb {
+    /// Creates a row in the `uploads` table. Chunks are added with `append_upload_chunk`.
+    async fn create_upload(
+        &self,
+        user_id: u32,
+        content_type: String,
+        size: u64,
+        sha256: String,
+        keep_metadata: bool,
+        expires_at: DateTime<Utc>,
+    ) -> Result<model::Upload, AccessError> {
+        let mut conn = self.conn().await?;
+        let upload_uuid = Uuid::new_v4();
+        let upload_id = sqlx::query!(
+            "INSERT INTO uploads (uuid, content_type, size, sha256, keep_metadata, expires_at, user_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            upload_uuid.to_string(),
+            content_type,
+            size,
+            sha256,
+            keep_metadata,
+            expires_at,
+            user_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_id();
+
+        let upload = sqlx::query_as!(
+            schema::Upload,
+            "SELECT * FROM uploads WHERE id = ?",
+            upload_id
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(upload)
+    }
+
+    /// Returns the user's upload, unless it has expired.
+    async fn get_upload_by_uuid(
+        &self,
+        user: &VerifiedUser,
+        upload_uuid: Uuid,
+    ) -> Result<model::Upload, AccessError> {
+        let mut conn = self.conn().await?;
+        let upload = sqlx::query_as!(
+            schema::Upload,
+            "SELECT * FROM uploads WHERE uuid = ? AND user_id = ? AND expires_at > CURRENT_TIMESTAMP",
+            upload_uuid.to_string(),
+            user.id()?
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(upload)
+    }
+
+    /// Returns the declared size of the user's unfinished uploads, counted against their quota.
+    async fn get_pending_upload_size(&self, user_id: u32) -> Result<u64, AccessError> {
+        let mut conn = self.conn().await?;
+        let size = sqlx::query_scalar!(
+            r#"SELECT CAST(COALESCE(SUM(size), 0) AS UNSIGNED) AS "size!: u64" FROM uploads WHERE user_id = ? AND expires_at > CURRENT_TIMESTAMP"#,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(size)
+    }
+
+    /// Records a chunk stored at `storage_key` and pushes back the upload's expiry.
+    /// The chunk only applies if the upload has received exactly `offset` bytes,
+    /// returns false if another chunk got there first.
+    /// Should be used within a transaction so the chunk and the upload's progress stay in step.
+    async fn append_upload_chunk(
+        &self,
+        upload_id: u32,
+        offset: u64,
+        size: u64,
+        storage_key: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, AccessError> {
+        let mut conn = self.conn().await?;
+        let result = sqlx::query!(
+            "UPDATE uploads SET received = received + ?, expires_at = ? WHERE id = ? AND received = ?",
+            size,
+            expires_at,
+            upload_id,
+            offset
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            "INSERT INTO upload_chunks (byte_offset, size, storage_key, upload_id) VALUES (?, ?, ?, ?)",
+            offset,
+            size,
+            storage_key,
+            upload_id
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Returns the storage keys of the upload's chunks, in order.
+    async fn get_upload_chunks(&self, upload_id: u32) -> Result<Vec<String>, AccessError> {
+        let mut conn = self.conn().await?;
+        let storage_keys = sqlx::query_scalar!(
+            "SELECT storage_key FROM upload_chunks WHERE upload_id = ? ORDER BY byte_offset",
+            upload_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(storage_keys)
+    }
+
+    /// Deletes the upload and its chunks, returning the storage keys of the chunks' blobs.
+    /// Should be used within a transaction so the upload isn't left half deleted.
+    async fn delete_upload(&self, upload_id: u32) -> Result<Vec<String>, AccessError> {
+        let mut conn = self.conn().await?;
+        let storage_keys = sqlx::query_scalar!(
+            "SELECT storage_key FROM upload_chunks WHERE upload_id = ?",
+            upload_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        sqlx::query!("DELETE FROM upload_chunks WHERE upload_id = ?", upload_id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query!("DELETE FROM uploads WHERE id = ?", upload_id)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(storage_keys)
+    }
+
+    /// Returns the ids of every user's uploads that expired before being finished.
+    async fn get_expired_upload_ids(&self) -> Result<Vec<u32>, AccessError> {
+        let mut conn = self.conn().await?;
+        let upload_ids =
+            sqlx::query_scalar!("SELECT id FROM uploads WHERE expires_at <= CURRENT_TIMESTAMP")
+                .fetch_all(&mut *conn)
+                .await?;
+
+        Ok(upload_ids)
+    }
+}
@@ -7,25 +7,29 @@ use super::{schema, AccessError, MemoryDb};
 
 #[async_trait]
 pub trait AccessUser {
-    async fn create_user(&self, name: String) -> Result<model::User, AccessError>;
+    async fn create_user(&self, uuid: Uuid, name: String) -> Result<model::User, AccessError>;
     async fn get_user(&self, user: &VerifiedUser) -> Result<model::User, AccessError>;
+    async fn get_user_by_id(&self, user_id: u32) -> Result<model::User, AccessError>;
+    async fn get_user_by_uuid(&self, user_uuid: Uuid) -> Result<model::User, AccessError>;
 }
 
 #[async_trait]
 impl AccessUser for MemoryDb {
-    async fn create_user(&self, name: String) -> Result<model::User, AccessError> {
-        let uuid = Uuid::new_v4();
+    /// Creates a row in the `users` table.
+    /// The `uuid` must match the user handle given to the authenticator during registration.
+    async fn create_user(&self, uuid: Uuid, name: String) -> Result<model::User, AccessError> {
+        let mut conn = self.conn().await?;
         let user_id = sqlx::query!(
             "INSERT INTO users (name, uuid) VALUES (?, ?)",
             name,
             uuid.to_string()
         )
-        .execute(&self.inner)
+        .execute(&mut *conn)
         .await?
         .last_insert_id();
 
         let user = sqlx::query_as!(schema::User, "SELECT * FROM users WHERE id = ?", user_id)
-            .fetch_one(&self.inner)
+            .fetch_one(&mut *conn)
             .await?
             .try_into()?;
 
@@ -33,11 +37,38 @@ impl AccessUser for MemoryDb {
     }
 
     async fn get_user(&self, user: &VerifiedUser) -> Result<model::User, AccessError> {
+        let mut conn = self.conn().await?;
         let user = sqlx::query_as!(schema::User, "SELECT * FROM users WHERE id = ?", user.id()?)
-            .fetch_one(&self.inner)
+            .fetch_one(&mut *conn)
             .await?
             .try_into()?;
 
         Ok(user)
     }
+
+    /// Returns the user with the provided id.
+    /// Prefer `get_user` once a [VerifiedUser] is available.
+    async fn get_user_by_id(&self, user_id: u32) -> Result<model::User, AccessError> {
+        let mut conn = self.conn().await?;
+        let user = sqlx::query_as!(schema::User, "SELECT * FROM users WHERE id = ?", user_id)
+            .fetch_one(&mut *conn)
+            .await?
+            .try_into()?;
+
+        Ok(user)
+    }
+
+    async fn get_user_by_uuid(&self, user_uuid: Uuid) -> Result<model::User, AccessError> {
+        let mut conn = self.conn().await?;
+        let user = sqlx::query_as!(
+            schema::User,
+            "SELECT * FROM users WHERE uuid = ?",
+            user_uuid.to_string()
+        )
+        .fetch_one(&mut *conn)
+        .await?
+        .try_into()?;
+
+        Ok(user)
+    }
 }
@@ -0,0 +1,149 @@
+use chrono::Utc;
+use uuid::Uuid;
+use webauthn_rs::prelude::{AuthenticationResult, Passkey};
+
+use crate::{
+    access::{
+        credential::AccessCredential, recovery::AccessRecoveryCode, session::AccessSession,
+        user::AccessUser,
+    },
+    auth,
+    error::ErrorCode,
+    model, AppError, AppResult,
+};
+
+use super::ActionError;
+
+/// Name given to a user's first passkey, which they can rename later.
+const DEFAULT_CREDENTIAL_NAME: &'static str = "Passkey";
+
+/// Creates the user described by a completed registration ceremony along with its first passkey.
+pub async fn register_user<A>(
+    db: &A,
+    user_uuid: Uuid,
+    name: String,
+    passkey: Passkey,
+) -> Result<model::User, ActionError>
+where
+    A: AccessUser + AccessCredential,
+{
+    let user = db.create_user(user_uuid, name).await?;
+    db.create_credential(user.id, DEFAULT_CREDENTIAL_NAME.into(), &passkey)
+        .await?;
+
+    Ok(user)
+}
+
+/// Records that a passkey was used in a completed authentication ceremony,
+/// persisting its updated signature counter.
+pub async fn record_authentication<A>(
+    db: &A,
+    user_id: u32,
+    result: &AuthenticationResult,
+) -> Result<(), ActionError>
+where
+    A: AccessCredential,
+{
+    for mut credential in db.get_user_credentials(user_id).await? {
+        if credential.passkey.update_credential(result).is_some() {
+            db.update_credential(credential.id, &credential.passkey)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts a new session for the user.
+///
+/// Returns the session token, which is not stored and can't be recovered later.
+pub async fn create_session<A>(
+    db: &A,
+    config: &auth::SessionConfig,
+    user_id: u32,
+    client: auth::ClientInfo,
+) -> Result<String, ActionError>
+where
+    A: AccessSession,
+{
+    let token = auth::generate_token();
+
+    db.create_session(
+        user_id,
+        auth::hash_token(&token),
+        client.device_label,
+        client.ip,
+        config.expires_at(Utc::now()),
+    )
+    .await?;
+
+    Ok(token)
+}
+
+/// Replaces the user's recovery codes with a freshly generated set.
+///
+/// Returns the codes in plain text. Only their hashes are stored, so they can't be shown again.
+pub async fn generate_recovery_codes<A>(db: &A, user_id: u32) -> Result<Vec<String>, AppError>
+where
+    A: AccessRecoveryCode,
+{
+    let (codes, code_hashes) = tokio::task::spawn_blocking(|| {
+        let codes: Vec<String> = (0..auth::RECOVERY_CODE_COUNT)
+            .map(|_| auth::generate_recovery_code())
+            .collect();
+        let code_hashes = codes
+            .iter()
+            .map(|code| auth::hash_recovery_code(code))
+            .collect::<AppResult<Vec<String>>>()?;
+
+        Ok::<_, AppError>((codes, code_hashes))
+    })
+    .await
+    .map_err(|_| {
+        AppError(
+            ErrorCode::Internal,
+            "Failed to generate recovery codes.".into(),
+        )
+    })??;
+
+    db.replace_recovery_codes(user_id, code_hashes).await?;
+
+    Ok(codes)
+}
+
+/// Redeems one of the user's unused recovery codes. Each code can only be redeemed once.
+///
+/// Returns an AppError if the code doesn't match any of the user's unused codes.
+pub async fn redeem_recovery_code<A>(db: &A, user_id: u32, code: String) -> Result<(), AppError>
+where
+    A: AccessRecoveryCode,
+{
+    let unused = db.get_unused_recovery_codes(user_id).await?;
+
+    let matched = tokio::task::spawn_blocking(move || {
+        unused
+            .into_iter()
+            .find(|c| auth::verify_recovery_code(&code, &c.code_hash))
+    })
+    .await
+    .map_err(|_| {
+        AppError(
+            ErrorCode::Internal,
+            "Failed to verify recovery code.".into(),
+        )
+    })?;
+
+    let redeemed = match matched {
+        Some(c) => db.use_recovery_code(c.id).await?,
+        None => false,
+    };
+
+    if !redeemed {
+        return Err(AppError(
+            ErrorCode::Unauthorized,
+            "Invalid recovery code.".into(),
+        ));
+    }
+
+    Ok(())
+}
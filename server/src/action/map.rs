@@ -0,0 +1,80 @@
+use crate::{access::story::AccessStory, api, auth::VerifiedUser, model, AppError};
+
+/// Most pins and clusters placed on the map at once.
+const MAX_MAP_MARKERS: u32 = 2000;
+/// Zoom level, as used by web map tiles, from which every story is shown on its own.
+const CLUSTER_MAX_ZOOM: u8 = 14;
+/// Clusters are grouped on a grid this many cells across each map tile, about 64 points per cell.
+const CLUSTER_CELLS_PER_TILE: f64 = 4.0;
+const MAX_ZOOM: u8 = 22;
+
+/// Returns the user's stories within `bbox`.
+/// Below [CLUSTER_MAX_ZOOM], stories sharing a grid cell are grouped into a cluster,
+/// which the database counts so clusters cover every story in the box.
+/// The grid is fixed to the zoom level rather than the box, so clusters stay put as the map pans.
+/// Without a `zoom`, it's estimated from the box as if the box were a single tile across.
+pub async fn get_story_map<A>(
+    db: &A,
+    user: &VerifiedUser,
+    bbox: model::BoundingBox,
+    zoom: Option<u8>,
+) -> Result<api::StoryMap, AppError>
+where
+    A: AccessStory,
+{
+    let zoom = zoom.unwrap_or_else(|| estimate_zoom(&bbox)).min(MAX_ZOOM);
+    let limit = MAX_MAP_MARKERS + 1;
+
+    if zoom >= CLUSTER_MAX_ZOOM {
+        let mut pins = Vec::new();
+        for part in bbox.split_at_antimeridian() {
+            pins.extend(db.list_story_pins(user, &part, limit).await?);
+        }
+        pins.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        let truncated = pins.len() > MAX_MAP_MARKERS as usize;
+        pins.truncate(MAX_MAP_MARKERS as usize);
+
+        return Ok(api::StoryMap {
+            stories: pins.into_iter().map(Into::into).collect(),
+            clusters: Vec::new(),
+            truncated,
+        });
+    }
+
+    // Cells line up with the antimeridian, so none is split between the box's parts.
+    let cell_size = 360.0 / 2f64.powi(zoom.into()) / CLUSTER_CELLS_PER_TILE;
+    let mut cells = Vec::new();
+    for part in bbox.split_at_antimeridian() {
+        cells.extend(db.list_story_cells(user, &part, cell_size, limit).await?);
+    }
+    cells.sort_by(|a, b| b.story.created_at.cmp(&a.story.created_at));
+    let truncated = cells.len() > MAX_MAP_MARKERS as usize;
+    cells.truncate(MAX_MAP_MARKERS as usize);
+
+    let mut map = api::StoryMap {
+        stories: Vec::new(),
+        clusters: Vec::new(),
+        truncated,
+    };
+    for cell in cells {
+        if cell.count == 1 {
+            map.stories.push(cell.story.into());
+        } else {
+            map.clusters.push(cell.into());
+        }
+    }
+
+    Ok(map)
+}
+
+/// Zoom level at which the box's width fills a single 256 point tile.
+fn estimate_zoom(bbox: &model::BoundingBox) -> u8 {
+    if bbox.width() <= 0.0 {
+        return MAX_ZOOM;
+    }
+
+    (360.0 / bbox.width())
+        .log2()
+        .floor()
+        .clamp(0.0, MAX_ZOOM.into()) as u8
+}
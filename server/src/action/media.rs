@@ -0,0 +1,928 @@
+use std::{
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageError};
+use sha2::{Digest, Sha256};
+use tokio::{process::Command, sync::mpsc, task::JoinError};
+use uuid::Uuid;
+
+use crate::{
+    access::{media::AccessMedia, AccessError, Transactional},
+    api,
+    auth::VerifiedUser,
+    blob::{self, BlobError, BlobStore, BlobStream, TempFile},
+    error::ErrorCode,
+    metadata, model, probe, AppError,
+};
+
+use super::upload::UploadConfig;
+
+/// Content types accepted by `POST /media`.
+pub const ALLOWED_CONTENT_TYPES: [&str; 14] = [
+    "image/jpeg",
+    "image/png",
+    "image/heic",
+    "image/gif",
+    "image/webp",
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/x-m4a",
+    "audio/aac",
+    "audio/wav",
+    "audio/ogg",
+    "audio/flac",
+    "video/mp4",
+    "video/quicktime",
+];
+
+/// Content types that can be played by audio content, see [ALLOWED_CONTENT_TYPES].
+pub const AUDIO_CONTENT_TYPES: [&str; 7] = [
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/x-m4a",
+    "audio/aac",
+    "audio/wav",
+    "audio/ogg",
+    "audio/flac",
+];
+
+/// Content types that can be played by video content, see [ALLOWED_CONTENT_TYPES].
+pub const VIDEO_CONTENT_TYPES: [&str; 2] = ["video/mp4", "video/quicktime"];
+
+/// Largest image accepted. Images are read into memory to strip their metadata and resize them.
+pub const MAX_IMAGE_SIZE: u64 = 100 * 1024 * 1024;
+/// Largest audio file accepted.
+pub const MAX_AUDIO_SIZE: u64 = 1024 * 1024 * 1024;
+/// Largest video accepted.
+pub const MAX_VIDEO_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Returns the largest file of the content type that's accepted, see [ALLOWED_CONTENT_TYPES].
+pub fn max_size(content_type: &str) -> u64 {
+    if VIDEO_CONTENT_TYPES.contains(&content_type) {
+        MAX_VIDEO_SIZE
+    } else if AUDIO_CONTENT_TYPES.contains(&content_type) {
+        MAX_AUDIO_SIZE
+    } else {
+        MAX_IMAGE_SIZE
+    }
+}
+
+/// Number of leading bytes kept while uploading to check an image's signature, see [has_image_signature].
+const SIGNATURE_LENGTH: usize = 12;
+
+/// Brands of an ISO base media file that hold HEIF images.
+const HEIF_BRANDS: [&[u8]; 7] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"mif1", b"msf1",
+];
+
+/// Returns whether a file starting with `head` is an image of the content type,
+/// so browsers can't be made to sniff an upload into something else.
+/// Other content types are checked by probing them, see [probe_media].
+fn has_image_signature(content_type: &str, head: &[u8]) -> bool {
+    match content_type {
+        "image/jpeg" => head.starts_with(&[0xff, 0xd8, 0xff]),
+        "image/png" => head.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/gif" => head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a"),
+        "image/webp" => head.starts_with(b"RIFF") && head.get(8..12) == Some(&b"WEBP"[..]),
+        "image/heic" => {
+            head.get(4..8) == Some(&b"ftyp"[..])
+                && head
+                    .get(8..12)
+                    .map_or(false, |brand| HEIF_BRANDS.contains(&brand))
+        }
+        _ => true,
+    }
+}
+
+/// Content types variants can be generated from. The rest are only served as uploaded.
+const RESIZABLE_CONTENT_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/// Returns whether media of the content type can be served without its metadata.
+/// Images can only once a copy without it has been made, which is only done for
+/// [RESIZABLE_CONTENT_TYPES], so other images have to be uploaded with `keep_metadata`.
+pub fn can_strip_metadata(content_type: &str) -> bool {
+    !content_type.starts_with("image/") || RESIZABLE_CONTENT_TYPES.contains(&content_type)
+}
+
+const VARIANT_CONTENT_TYPE: &str = "image/jpeg";
+const VARIANT_JPEG_QUALITY: u8 = 85;
+
+const POSTER_CONTENT_TYPE: &str = "image/jpeg";
+
+/// How long media can go without anything referring to it before it's removed,
+/// giving clients time to add an upload to a story.
+const UNREFERENCED_MEDIA_GRACE: Duration = Duration::from_secs(60 * 60 * 24);
+/// How often unreferenced media is looked for.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long ffmpeg gets to extract a poster before it's killed, so one video can't stall the queue.
+const POSTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Media waiting for its variants or poster to be generated, see [generate_variants_forever].
+pub type VariantQueue = mpsc::UnboundedSender<model::Media>;
+
+/// Stores an upload in the blob store and records it as the user's media.
+/// The blob is removed again if it can't be recorded.
+/// Once recorded, its EXIF is read and a copy without metadata is stored,
+/// then the media is queued for variant generation, or for poster extraction if it's video.
+///
+/// Audio and video are rejected unless they can be read, see [probe::probe_audio] and [probe::probe_video].
+/// Images whose metadata can't be removed are rejected unless `keep_metadata` is set, see [can_strip_metadata].
+/// When `expected_sha256` is set the upload is rejected unless its hash matches.
+/// If the user has uploaded the same file before, the new blob is dropped and the existing media,
+/// along with its `keep_metadata` choice, is returned instead.
+/// Images that don't start with their content type's signature are rejected before that.
+/// Otherwise it's rejected if it's larger than its content type allows, see [max_size],
+/// or if it would take the user's media past their storage quota.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_media<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    variants: &VariantQueue,
+    uploads: &UploadConfig,
+    user: &VerifiedUser,
+    content_type: String,
+    keep_metadata: bool,
+    expected_sha256: Option<&str>,
+    body: BlobStream<'_>,
+) -> Result<api::Media, AppError>
+where
+    A: AccessMedia,
+{
+    if !keep_metadata && !can_strip_metadata(&content_type) {
+        return Err(AppError(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Metadata can't be removed from {}, upload it with `keep_metadata` or as JPEG, PNG, GIF or WebP.",
+                content_type
+            ),
+        ));
+    }
+
+    let media_uuid = Uuid::new_v4();
+    let storage_key = media_uuid.to_string();
+
+    // The body only borrows the hasher, so it's free to finalize once the body has been stored.
+    let mut hasher = Sha256::new();
+    let mut head = Vec::with_capacity(SIGNATURE_LENGTH);
+    let body = body
+        .inspect_ok(|chunk| {
+            hasher.update(chunk);
+            let missing = SIGNATURE_LENGTH.saturating_sub(head.len());
+            head.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        })
+        .boxed();
+    let size = blobs.put(&storage_key, body).await?;
+    let sha256 = hex::encode(hasher.finalize());
+
+    if let Some(expected_sha256) = expected_sha256 {
+        if !sha256.eq_ignore_ascii_case(expected_sha256) {
+            blobs.delete(&storage_key).await?;
+            return Err(AppError(
+                ErrorCode::ValidationFailed,
+                "Upload doesn't match its SHA-256.".into(),
+            ));
+        }
+    }
+
+    if !has_image_signature(&content_type, &head) {
+        blobs.delete(&storage_key).await?;
+        return Err(AppError(
+            ErrorCode::ValidationFailed,
+            format!("File isn't {} as its content type says.", content_type),
+        ));
+    }
+
+    if let Some(media) = find_media_by_sha256(db, user, &sha256).await? {
+        blobs.delete(&storage_key).await?;
+        return Ok(media);
+    }
+
+    let max_size = max_size(&content_type);
+    if size > max_size {
+        blobs.delete(&storage_key).await?;
+        return Err(AppError(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "Files of type {} are limited to {} bytes.",
+                content_type, max_size
+            ),
+        ));
+    }
+    if let Err(err) = check_storage_quota(db, uploads, user, size).await {
+        blobs.delete(&storage_key).await?;
+        return Err(err);
+    }
+
+    let media_probe = match probe_media(blobs, &storage_key, &content_type).await {
+        Ok(media_probe) => media_probe,
+        Err(err) => {
+            blobs.delete(&storage_key).await?;
+            return Err(err);
+        }
+    };
+
+    let mut media = match db
+        .create_media(
+            user.id()?,
+            media_uuid,
+            content_type,
+            size,
+            storage_key.clone(),
+            sha256.clone(),
+            keep_metadata,
+        )
+        .await
+    {
+        Ok(media) => media,
+        // The same file was stored by a concurrent upload since it was looked up.
+        Err(AccessError::Sql(sqlx::Error::Database(err))) if err.is_unique_violation() => {
+            blobs.delete(&storage_key).await?;
+            return find_media_by_sha256(db, user, &sha256)
+                .await?
+                .ok_or(AppError(
+                    ErrorCode::Conflict,
+                    "Media was removed while it was being uploaded.".into(),
+                ));
+        }
+        Err(err) => {
+            blobs.delete(&storage_key).await?;
+            return Err(err.into());
+        }
+    };
+
+    if let Some(media_probe) = media_probe {
+        db.update_media_probe(media.id, &media_probe).await?;
+        media.probe = media_probe;
+    }
+
+    // The upload is still usable without its metadata, so failing to read it isn't fatal.
+    if media.content_type.starts_with("image/") {
+        media = match extract_metadata(db, blobs, media.clone()).await {
+            Ok(media) => media,
+            Err(err) => {
+                tracing::error!(media = %media.uuid, error = ?err, "failed to read metadata");
+                media
+            }
+        };
+    }
+
+    if RESIZABLE_CONTENT_TYPES.contains(&media.content_type.as_str())
+        || VIDEO_CONTENT_TYPES.contains(&media.content_type.as_str())
+    {
+        // Only fails if the worker has stopped, the original is still usable without variants.
+        if variants.send(media.clone()).is_err() {
+            tracing::error!(media = %media.uuid, "variant worker isn't running");
+        }
+    }
+
+    Ok(media.into())
+}
+
+/// Fails unless `size` more bytes of media fit within the user's storage quota.
+pub async fn check_storage_quota<A>(
+    db: &A,
+    uploads: &UploadConfig,
+    user: &VerifiedUser,
+    size: u64,
+) -> Result<(), AppError>
+where
+    A: AccessMedia,
+{
+    let stored = db.get_stored_media_size(user.id()?).await?;
+    if stored.saturating_add(size) > uploads.storage_quota {
+        return Err(AppError(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "Media is limited to {} bytes, {} are already in use.",
+                uploads.storage_quota, stored
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Reads the duration and codec of audio or video, making sure it really is what it claims to be.
+/// Returns nothing for other content types.
+async fn probe_media(
+    blobs: &dyn BlobStore,
+    storage_key: &str,
+    content_type: &str,
+) -> Result<Option<model::MediaProbe>, AppError> {
+    if VIDEO_CONTENT_TYPES.contains(&content_type) {
+        return probe_video(blobs, storage_key).await.map(Some);
+    }
+    if !AUDIO_CONTENT_TYPES.contains(&content_type) {
+        return Ok(None);
+    }
+
+    let temp = TempFile::download(blobs, storage_key).await?;
+    let content_type = content_type.to_string();
+    let probed =
+        tokio::task::spawn_blocking(move || probe::probe_audio(&content_type, temp.path()))
+            .await
+            .map_err(|err| {
+                tracing::error!(error = ?err, "audio probe panicked");
+                AppError(ErrorCode::Internal, "Internal server error".into())
+            })?;
+
+    match probed {
+        Ok(media_probe) => Ok(Some(media_probe)),
+        Err(err) => {
+            tracing::debug!(error = ?err, "rejected audio upload");
+            Err(AppError(
+                ErrorCode::ValidationFailed,
+                "File isn't audio that can be played.".into(),
+            ))
+        }
+    }
+}
+
+/// Reads the duration, codec and dimensions of video from a temporary copy of the upload.
+async fn probe_video(
+    blobs: &dyn BlobStore,
+    storage_key: &str,
+) -> Result<model::MediaProbe, AppError> {
+    let temp = TempFile::download(blobs, storage_key).await?;
+    let probed = tokio::task::spawn_blocking(move || probe::probe_video(temp.path()))
+        .await
+        .map_err(|err| {
+            tracing::error!(error = ?err, "video probe panicked");
+            AppError(ErrorCode::Internal, "Internal server error".into())
+        })?;
+
+    probed.map_err(|err| {
+        tracing::debug!(error = ?err, "rejected video upload");
+        AppError(
+            ErrorCode::ValidationFailed,
+            "File isn't video that can be played.".into(),
+        )
+    })
+}
+
+/// Returns the user's media with the given SHA-256, letting a client skip uploading a file again.
+/// Hashes are only matched against the user's own uploads,
+/// sharing them would tell users which files others have uploaded.
+/// Media nothing refers to yet is touched, so it isn't collected before the client refers to it.
+pub async fn find_media_by_sha256<A>(
+    db: &A,
+    user: &VerifiedUser,
+    sha256: &str,
+) -> Result<Option<api::Media>, AppError>
+where
+    A: AccessMedia,
+{
+    let media = db
+        .get_media_by_sha256(user.id()?, &sha256.to_ascii_lowercase())
+        .await?;
+
+    if let Some(media) = &media {
+        if media.ref_count == 0 {
+            db.touch_media(media.id).await?;
+        }
+    }
+
+    Ok(media.map(Into::into))
+}
+
+/// Removes the blobs of media that nothing refers to anymore, see [AccessMedia::delete_unreferenced_media].
+/// Must be called after the transaction that dropped the references has been committed,
+/// the rows are gone by then so a failure here only leaves an orphaned blob.
+///
+/// Returns how many of the media were removed.
+pub async fn collect_media<A>(db: &A, blobs: &dyn BlobStore, media_ids: Vec<u32>) -> usize
+where
+    A: AccessMedia + Transactional,
+{
+    let mut collected = 0;
+    for media_id in media_ids {
+        let storage_keys = match delete_unreferenced_media(db, media_id).await {
+            Ok(storage_keys) if storage_keys.is_empty() => continue,
+            Ok(storage_keys) => storage_keys,
+            Err(err) => {
+                tracing::error!(media_id, error = ?err, "failed to collect media");
+                continue;
+            }
+        };
+        collected += 1;
+
+        for storage_key in storage_keys {
+            if let Err(err) = blobs.delete(&storage_key).await {
+                tracing::error!(storage_key, error = ?err, "failed to delete blob");
+            }
+        }
+    }
+
+    collected
+}
+
+/// Removes every user's media that nothing has referred to for [UNREFERENCED_MEDIA_GRACE],
+/// like uploads never added to a story, or the posters of content that has since been removed.
+///
+/// Returns how many were removed.
+pub async fn collect_unreferenced_media<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+) -> Result<usize, AccessError>
+where
+    A: AccessMedia + Transactional,
+{
+    let grace = chrono::Duration::from_std(UNREFERENCED_MEDIA_GRACE)
+        .unwrap_or(chrono::Duration::max_value());
+    let media_ids = db.get_unreferenced_media_ids(Utc::now() - grace).await?;
+
+    Ok(collect_media(db, blobs, media_ids).await)
+}
+
+/// Runs [collect_unreferenced_media] on an interval, forever. Intended to be spawned at startup.
+pub async fn collect_media_periodically<A>(db: A, blobs: Arc<dyn BlobStore>)
+where
+    A: AccessMedia + Transactional + Send + Sync,
+{
+    let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match collect_unreferenced_media(&db, blobs.as_ref()).await {
+            Ok(0) => {}
+            Ok(collected) => tracing::info!("Removed {} unreferenced media", collected),
+            Err(err) => tracing::error!(error = ?err, "failed to remove unreferenced media"),
+        }
+    }
+}
+
+async fn delete_unreferenced_media<A>(db: &A, media_id: u32) -> Result<Vec<String>, AccessError>
+where
+    A: AccessMedia + Transactional,
+{
+    let tx = db.begin().await?;
+    let storage_keys = tx.delete_unreferenced_media(media_id).await?;
+    tx.commit().await?;
+
+    Ok(storage_keys)
+}
+
+/// A single range of a `Range: bytes=...` header. Requests for several ranges aren't supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=start-end`, both inclusive.
+    Bounded(u64, u64),
+    /// `bytes=start-`, up to the end of the file.
+    From(u64),
+    /// `bytes=-length`, the last `length` bytes of the file.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Returns the bytes the range covers in a file of `size` bytes, or nothing if it covers none.
+    fn resolve(&self, size: u64) -> Option<Range<u64>> {
+        let range = match *self {
+            ByteRange::Bounded(start, end) if start <= end => {
+                start..end.saturating_add(1).min(size)
+            }
+            ByteRange::Bounded(..) => return None,
+            ByteRange::From(start) => start..size,
+            ByteRange::Suffix(length) => size.saturating_sub(length)..size,
+        };
+
+        if range.start < range.end {
+            Some(range)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = s.trim().strip_prefix("bytes=").ok_or(())?;
+        if spec.contains(',') {
+            return Err(());
+        }
+
+        let (start, end) = spec.split_once('-').ok_or(())?;
+        let (start, end) = (start.trim(), end.trim());
+        let parse = |value: &str| value.parse::<u64>().map_err(|_| ());
+        match (start.is_empty(), end.is_empty()) {
+            (false, false) => Ok(ByteRange::Bounded(parse(start)?, parse(end)?)),
+            (false, true) => Ok(ByteRange::From(parse(start)?)),
+            (true, false) => Ok(ByteRange::Suffix(parse(end)?)),
+            (true, true) => Err(()),
+        }
+    }
+}
+
+/// A file ready to be sent to the client.
+pub struct MediaDownload {
+    pub content_type: String,
+    /// Size of the whole file.
+    pub size: u64,
+    /// Part of the file `body` holds, when only part of it was requested.
+    pub range: Option<Range<u64>>,
+    /// Set when the requested range isn't within the file, `body` is empty then.
+    pub unsatisfiable: bool,
+    /// Strong validator of the file, which `If-Range` is compared against.
+    pub etag: String,
+    /// Set when the requested variant hasn't been generated yet and something else is sent
+    /// in its place, which clients shouldn't hold on to.
+    pub fallback: bool,
+    pub body: BlobStream<'static>,
+}
+
+/// Returns the user's media, or the requested variant of it.
+/// Falls back to the original while the variant hasn't been generated.
+/// An image whose metadata couldn't be removed is served as its largest variant instead,
+/// and not at all until that has been generated.
+/// With a `range`, only those bytes are returned, letting players seek without downloading the whole file.
+/// The range is ignored when `if_range` doesn't match the file's ETag, as the client's copy is outdated.
+pub async fn get_media<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    user: &VerifiedUser,
+    media_uuid: Uuid,
+    size: Option<model::VariantSize>,
+    range: Option<ByteRange>,
+    if_range: Option<&str>,
+) -> Result<MediaDownload, AppError>
+where
+    A: AccessMedia,
+{
+    let media = db.get_media_by_uuid(user, media_uuid).await?;
+
+    let variant = match size {
+        Some(size) => db.get_media_variant(user, media_uuid, size).await?,
+        None => None,
+    };
+
+    let variant = match (variant, media.served()) {
+        (None, None) => {
+            db.get_media_variant(user, media_uuid, model::VariantSize::Full)
+                .await?
+        }
+        (variant, _) => variant,
+    };
+
+    let fallback = match (size, &variant) {
+        (Some(size), Some(variant)) => variant.size != size,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+
+    let (storage_key, content_type, size) = match (variant, media.served()) {
+        (Some(variant), _) => (variant.storage_key, variant.content_type, variant.byte_size),
+        (None, Some((storage_key, size))) => {
+            (storage_key.to_string(), media.content_type.clone(), size)
+        }
+        (None, None) => {
+            return Err(AppError(
+                ErrorCode::Conflict,
+                "Media can't be served without its metadata, upload it with `keep_metadata` or as JPEG, PNG, GIF or WebP."
+                    .into(),
+            ))
+        }
+    };
+
+    let mut download = download(blobs, &storage_key, content_type, size, range, if_range).await?;
+    download.fallback = fallback;
+    Ok(download)
+}
+
+/// Returns the frame extracted from the user's video, see [extract_poster].
+pub async fn get_media_poster<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    user: &VerifiedUser,
+    media_uuid: Uuid,
+) -> Result<MediaDownload, AppError>
+where
+    A: AccessMedia,
+{
+    let media = db.get_media_by_uuid(user, media_uuid).await?;
+    let storage_key = media
+        .poster_storage_key
+        .ok_or(AppError(ErrorCode::NotFound, "Media has no poster.".into()))?;
+
+    // Posters are small and their size isn't recorded, so it's read whole.
+    let data = blob::read_all(blobs, &storage_key).await?;
+    let size = data.len() as u64;
+
+    Ok(MediaDownload {
+        content_type: POSTER_CONTENT_TYPE.into(),
+        size,
+        range: None,
+        unsatisfiable: false,
+        etag: etag(&storage_key, size),
+        fallback: false,
+        body: stream::once(async move { Ok(Bytes::from(data)) }).boxed(),
+    })
+}
+
+async fn download(
+    blobs: &dyn BlobStore,
+    storage_key: &str,
+    content_type: String,
+    size: u64,
+    range: Option<ByteRange>,
+    if_range: Option<&str>,
+) -> Result<MediaDownload, AppError> {
+    let etag = etag(storage_key, size);
+    // A weak or date validator never matches, which sends the whole file as HTTP allows.
+    let range = range.filter(|_| if_range.map_or(true, |if_range| if_range == etag));
+
+    let (range, unsatisfiable) = match range.map(|range| range.resolve(size)) {
+        Some(None) => (None, true),
+        Some(range) => (range, false),
+        None => (None, false),
+    };
+
+    let body = if unsatisfiable {
+        stream::empty().boxed()
+    } else {
+        match &range {
+            Some(range) => blobs.get_range(storage_key, range.clone()).await?,
+            None => blobs.get(storage_key).await?,
+        }
+    };
+
+    Ok(MediaDownload {
+        content_type,
+        size,
+        range,
+        unsatisfiable,
+        etag,
+        fallback: false,
+        body,
+    })
+}
+
+/// A storage key only ever holds one file, a regenerated variant or poster being made from the
+/// same original, so the key and size identify the bytes served.
+fn etag(storage_key: &str, size: u64) -> String {
+    format!("\"{}-{}\"", storage_key, size)
+}
+
+/// Queues media whose variants or poster were never generated,
+/// e.g. because the server stopped before the worker got to it. Intended to be run at startup.
+///
+/// Returns how many were queued.
+pub async fn enqueue_unprocessed_media<A>(
+    db: &A,
+    variants: &VariantQueue,
+) -> Result<usize, AccessError>
+where
+    A: AccessMedia,
+{
+    let mut queued = 0;
+    for media in db.get_unprocessed_media().await? {
+        if !RESIZABLE_CONTENT_TYPES.contains(&media.content_type.as_str())
+            && !VIDEO_CONTENT_TYPES.contains(&media.content_type.as_str())
+        {
+            continue;
+        }
+        if variants.send(media).is_err() {
+            tracing::error!("variant worker isn't running");
+            break;
+        }
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+/// Generates variants for queued images, and posters for queued video, one at a time,
+/// until the queue is closed. Posters are only extracted when an `ffmpeg` binary is configured.
+/// Intended to be spawned at startup.
+pub async fn generate_variants_forever<A>(
+    db: A,
+    blobs: Arc<dyn BlobStore>,
+    ffmpeg: Option<PathBuf>,
+    mut queue: mpsc::UnboundedReceiver<model::Media>,
+) where
+    A: AccessMedia + Send + Sync,
+{
+    while let Some(media) = queue.recv().await {
+        if !VIDEO_CONTENT_TYPES.contains(&media.content_type.as_str()) {
+            if let Err(err) = generate_variants(&db, blobs.as_ref(), &media).await {
+                tracing::error!(media = %media.uuid, error = ?err, "failed to generate variants");
+            }
+            continue;
+        }
+
+        if let Some(ffmpeg) = &ffmpeg {
+            if let Err(err) = extract_poster(&db, blobs.as_ref(), ffmpeg, &media).await {
+                tracing::error!(media = %media.uuid, error = ?err, "failed to extract poster");
+                // Unlike the blob store or database failing, trying again wouldn't get any further.
+                if matches!(err, ProcessingError::Ffmpeg(_) | ProcessingError::Timeout) {
+                    if let Err(err) = db.mark_media_poster_failed(media.id).await {
+                        tracing::error!(media = %media.uuid, error = ?err, "failed to mark poster as failed");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ProcessingError {
+    Blob(BlobError),
+    Image(ImageError),
+    Access(AccessError),
+    Worker(JoinError),
+    Process(std::io::Error),
+    /// ffmpeg exited unsuccessfully, with what it wrote to stderr.
+    Ffmpeg(String),
+    /// ffmpeg was killed for taking longer than [POSTER_TIMEOUT].
+    Timeout,
+}
+
+/// Reads the media's EXIF and stores a copy of it without metadata, when the format allows.
+/// The EXIF is read from a temporary copy of the upload, which is only read into memory
+/// if a copy can be made, see [MAX_IMAGE_SIZE].
+async fn extract_metadata<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    mut media: model::Media,
+) -> Result<model::Media, ProcessingError>
+where
+    A: AccessMedia,
+{
+    let temp = TempFile::download(blobs, &media.storage_key)
+        .await
+        .map_err(ProcessingError::Blob)?;
+
+    let content_type = media.content_type.clone();
+    let (media_metadata, stripped) = tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(temp.path())?;
+        let media_metadata = metadata::read_metadata(&mut std::io::BufReader::new(file));
+        // Only the formats a copy can be made of are read whole.
+        let stripped = if RESIZABLE_CONTENT_TYPES.contains(&content_type.as_str()) {
+            let original = std::fs::read(temp.path())?;
+            metadata::strip_metadata(&content_type, &original, media_metadata.orientation)
+        } else {
+            Ok(None)
+        };
+        Ok::<_, std::io::Error>((media_metadata, stripped))
+    })
+    .await
+    .map_err(ProcessingError::Worker)?
+    .map_err(|err| ProcessingError::Blob(err.into()))?;
+
+    let stripped = match stripped.map_err(ProcessingError::Image)? {
+        Some(data) => {
+            let storage_key = format!("{}_stripped", media.uuid);
+            let body = stream::once(async move { Ok(Bytes::from(data)) }).boxed();
+            let size = blobs
+                .put(&storage_key, body)
+                .await
+                .map_err(ProcessingError::Blob)?;
+            Some((storage_key, size))
+        }
+        None => None,
+    };
+
+    db.update_media_metadata(media.id, &media_metadata, stripped.clone())
+        .await
+        .map_err(ProcessingError::Access)?;
+
+    media.metadata = media_metadata;
+    media.stripped = stripped;
+    Ok(media)
+}
+
+/// Picks a representative frame of the video with ffmpeg and stores it as the media's poster.
+/// ffmpeg is killed if it takes longer than [POSTER_TIMEOUT].
+async fn extract_poster<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    ffmpeg: &Path,
+    media: &model::Media,
+) -> Result<(), ProcessingError>
+where
+    A: AccessMedia,
+{
+    // ffmpeg needs to seek within MP4s, which it can't do on a pipe.
+    let temp = TempFile::download(blobs, &media.storage_key)
+        .await
+        .map_err(ProcessingError::Blob)?;
+
+    let command = Command::new(ffmpeg)
+        .args(["-v", "error", "-i"])
+        .arg(temp.path())
+        .args([
+            "-vf",
+            "thumbnail",
+            "-frames:v",
+            "1",
+            "-f",
+            "image2",
+            "-c:v",
+            "mjpeg",
+            "pipe:1",
+        ])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    // Dropping the command on timeout kills ffmpeg, see `kill_on_drop`.
+    let output = tokio::time::timeout(POSTER_TIMEOUT, command)
+        .await
+        .map_err(|_| ProcessingError::Timeout)?
+        .map_err(ProcessingError::Process)?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(ProcessingError::Ffmpeg(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+
+    let storage_key = format!("{}_poster", media.uuid);
+    let body = stream::once(async move { Ok(Bytes::from(output.stdout)) }).boxed();
+    blobs
+        .put(&storage_key, body)
+        .await
+        .map_err(ProcessingError::Blob)?;
+
+    db.update_media_poster(media.id, storage_key)
+        .await
+        .map_err(ProcessingError::Access)
+}
+
+async fn generate_variants<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    media: &model::Media,
+) -> Result<(), ProcessingError>
+where
+    A: AccessMedia,
+{
+    let original = blob::read_all(blobs, &media.storage_key)
+        .await
+        .map_err(ProcessingError::Blob)?;
+
+    // Decoding and resizing is CPU bound, keep it off the async workers.
+    let orientation = media.metadata.orientation;
+    let rendered = tokio::task::spawn_blocking(move || render_variants(&original, orientation))
+        .await
+        .map_err(ProcessingError::Worker)?
+        .map_err(ProcessingError::Image)?;
+
+    for (size, width, height, data) in rendered {
+        let storage_key = format!("{}_{}.jpg", media.uuid, size.as_str());
+        let body = stream::once(async move { Ok(Bytes::from(data)) }).boxed();
+        let byte_size = blobs
+            .put(&storage_key, body)
+            .await
+            .map_err(ProcessingError::Blob)?;
+
+        db.create_media_variant(
+            media.id,
+            size,
+            width,
+            height,
+            VARIANT_CONTENT_TYPE.into(),
+            byte_size,
+            storage_key,
+        )
+        .await
+        .map_err(ProcessingError::Access)?;
+    }
+
+    Ok(())
+}
+
+/// Encodes each variant size as a JPEG, returning its size, dimensions and bytes.
+/// Variants are rotated upright and carry no metadata.
+fn render_variants(
+    original: &[u8],
+    orientation: Option<u16>,
+) -> Result<Vec<(model::VariantSize, u32, u32, Vec<u8>)>, ImageError> {
+    let image = metadata::apply_orientation(image::load_from_memory(original)?, orientation);
+
+    let mut rendered = Vec::new();
+    for size in model::VariantSize::ALL {
+        let max = size.max_dimension();
+        let resized = match image.width() > max || image.height() > max {
+            true => image.resize(max, max, image::imageops::FilterType::Lanczos3),
+            false => image.clone(),
+        };
+        let resized = DynamicImage::ImageRgb8(resized.to_rgb8());
+
+        let mut data = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut data, VARIANT_JPEG_QUALITY).encode_image(&resized)?;
+
+        rendered.push((size, resized.width(), resized.height(), data.into_inner()));
+    }
+
+    Ok(rendered)
+}
@@ -1,15 +1,22 @@
-use axum::http::StatusCode;
 use uuid::Uuid;
 
 use crate::{
-    access::{self, AccessError},
+    access::{self, media::AccessMedia, revision::AccessRevision, AccessError, Transactional},
     api,
     auth::VerifiedUser,
-    model, AppError,
+    error::ErrorCode,
+    model, validation, AppError,
 };
 
+pub mod auth;
+pub mod map;
+pub mod media;
 pub mod prompts;
+pub mod revision;
+pub mod trash;
+pub mod upload;
 
+#[derive(Debug)]
 pub enum ActionError {
     AccessError(access::AccessError),
 }
@@ -20,19 +27,34 @@ impl From<AccessError> for ActionError {
     }
 }
 
+/// Creates a story and its content as a single transaction, recorded as the story's first revision.
 pub async fn create_story<A>(
     db: &A,
     user: &VerifiedUser,
     title: String,
-    content: Vec<api::ContentDetails>,
+    location: Option<api::StoryLocation>,
+    mut content: Vec<api::ContentDetails>,
 ) -> Result<api::Story, ActionError>
 where
-    A: access::story::AccessStory,
+    A: access::story::AccessStory + AccessRevision + AccessMedia + Transactional,
 {
-    let story = db.create_story(user, title).await?;
-    let content = db.create_content(story.id, content).await?;
+    let tx = db.begin().await?;
 
-    Ok(api::Story::new(story, content))
+    for details in content.iter_mut() {
+        resolve_media(&tx, user, details).await?;
+    }
+
+    let story = tx.create_story(user, title).await?;
+    if let Some(location) = location {
+        tx.set_story_location(story.id, Some(location.into()))
+            .await?;
+    }
+    let content = tx.create_content(user, story.id, 0, content).await?;
+    tx.create_revision(&story, &content).await?;
+
+    tx.commit().await?;
+
+    Ok(story_response(db, user, story, content).await?)
 }
 
 pub async fn get_story<A>(
@@ -41,73 +63,471 @@ pub async fn get_story<A>(
     story_uuid: Uuid,
 ) -> Result<api::Story, ActionError>
 where
-    A: access::story::AccessStory,
+    A: access::story::AccessStory + AccessMedia,
 {
     let story = db.get_story_by_uuid(user, story_uuid).await?;
-    let content = db.get_story_content(story.id).await?;
+    let content = db.get_story_content(user, story.id).await?;
 
-    Ok(api::Story::new(story, content))
+    Ok(story_response(db, user, story, content).await?)
 }
 
+/// Returns a page of the user's stories, newest first.
+/// Content is only loaded when `include_content` is set, otherwise each story's content is empty.
+pub async fn list_stories<A>(
+    db: &A,
+    user: &VerifiedUser,
+    cursor: Option<api::StoryCursor>,
+    limit: u32,
+    include_content: bool,
+) -> Result<api::StoryPage, ActionError>
+where
+    A: access::story::AccessStory + AccessMedia,
+{
+    // Fetch one extra story to find out whether there's another page.
+    let before = cursor.map(|c| (c.created_at, c.id));
+    let mut stories = db.list_stories(user, before, limit + 1).await?;
+
+    let next_cursor = if stories.len() > limit as usize {
+        stories.truncate(limit as usize);
+        stories.last().map(|s| {
+            api::StoryCursor {
+                created_at: s.created_at,
+                id: s.id,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    let mut page = Vec::new();
+    for story in stories {
+        let content = match include_content {
+            true => db.get_story_content(user, story.id).await?,
+            false => Vec::new(),
+        };
+        page.push(story_response(db, user, story, content).await?);
+    }
+
+    Ok(api::StoryPage {
+        stories: page,
+        next_cursor,
+    })
+}
+
+/// Applies a title change, location change and content operations to a story as a single transaction.
+/// `location` is only changed when set, to `None` if the location is being removed.
+/// When `expected_version` is set the story must still be at that version.
+/// The result is recorded as a new revision.
 pub async fn update_story<A>(
     db: &A,
     user: &VerifiedUser,
-    mut story: model::Story,
+    story_uuid: Uuid,
+    expected_version: Option<u32>,
     title: Option<String>,
-    deleted: Option<bool>,
-) -> Result<(), AppError>
+    location: Option<Option<api::StoryLocation>>,
+    content_operations: Vec<ContentOperation>,
+) -> Result<api::Story, AppError>
 where
-    A: access::story::AccessStory,
+    A: access::story::AccessStory + AccessRevision + AccessMedia + Transactional,
 {
-    if let Some(title) = title.clone() {
+    let tx = db.begin().await?;
+
+    let mut story = tx.get_story_by_uuid(user, story_uuid).await?;
+    check_version(&story, expected_version)?;
+    if let Some(title) = title {
         story.title = title;
     }
-
-    if let Some(delete) = deleted.clone() {
-        story.deleted = delete;
+    let story = tx.update_story(user, story).await?;
+    if let Some(location) = location {
+        tx.set_story_location(story.id, location.map(Into::into))
+            .await?;
     }
 
-    db.update_story(user, story).await?;
+    update_content(&tx, user, &story, content_operations).await?;
 
-    Ok(())
+    let content = tx.get_story_content(user, story.id).await?;
+    tx.create_revision(&story, &content).await?;
+
+    tx.commit().await?;
+
+    Ok(story_response(db, user, story, content).await?)
 }
 
-pub async fn delete_story<A>(db: &A, user: &VerifiedUser, story_uuid: Uuid) -> Result<(), AppError>
+/// When `expected_version` is set the story must still be at that version.
+pub async fn delete_story<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+    expected_version: Option<u32>,
+) -> Result<(), AppError>
 where
-    A: access::story::AccessStory,
+    A: access::story::AccessStory + Transactional,
 {
-    let mut story = db.get_story_by_uuid(user, story_uuid).await?;
+    let tx = db.begin().await?;
+
+    let mut story = tx.get_story_by_uuid(user, story_uuid).await?;
+    check_version(&story, expected_version)?;
     story.deleted = true;
 
-    db.update_story(user, story).await?;
+    tx.update_story(user, story).await?;
+
+    tx.commit().await?;
 
     Ok(())
 }
 
-pub struct ContentUpdate {
-    pub uuid: Uuid,
-    pub content: api::ContentDetails,
+/// Builds the api representation of a story, listing the variants of any media its images refer to.
+async fn story_response<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story: model::Story,
+    content: Vec<model::Content>,
+) -> Result<api::Story, AccessError>
+where
+    A: access::story::AccessStory + AccessMedia,
+{
+    let location = db.get_story_location(story.id).await?;
+    let mut story = api::Story::new(story, content);
+    story.location = location.map(Into::into);
+    for content in story.content.iter_mut() {
+        if let api::ContentDetails::Video(video) = &mut content.details {
+            video.poster_url = match video.poster {
+                Some(poster) => Some(api::Media::url(poster)),
+                None => match db.get_media_by_uuid(user, video.media).await {
+                    Ok(media) => media
+                        .poster_storage_key
+                        .map(|_| api::Media::poster_url(media.uuid)),
+                    Err(AccessError::Sql(sqlx::Error::RowNotFound)) => None,
+                    Err(err) => return Err(err),
+                },
+            };
+            continue;
+        }
+
+        if let api::ContentDetails::Image(image) = &mut content.details {
+            if let Some(media_uuid) = image.media {
+                match db.get_media_by_uuid(user, media_uuid).await {
+                    Ok(media) => {
+                        image.captured_at = media.metadata.captured_at;
+                        image.latitude = media.metadata.latitude;
+                        image.longitude = media.metadata.longitude;
+                    }
+                    // The content still renders from `src` if its media is gone.
+                    Err(AccessError::Sql(sqlx::Error::RowNotFound)) => continue,
+                    Err(err) => return Err(err),
+                }
+                image.variants = db
+                    .get_media_variants(user, media_uuid)
+                    .await?
+                    .into_iter()
+                    .map(|v| v.into_api(media_uuid))
+                    .collect();
+            }
+        }
+    }
+
+    Ok(story)
 }
 
-pub async fn update_content<A>(
+/// Makes sure any media the content refers to was uploaded by the user and suits the content,
+/// then fills in what was read from the media when it was uploaded.
+async fn resolve_media<A>(
     db: &A,
+    user: &VerifiedUser,
+    details: &mut api::ContentDetails,
+) -> Result<(), AccessError>
+where
+    A: AccessMedia,
+{
+    let media = match details.media() {
+        Some(media_uuid) => db.get_media_by_uuid(user, media_uuid).await?,
+        None => return Ok(()),
+    };
+
+    let mismatch =
+        |message: &str| AccessError::App(AppError(ErrorCode::ValidationFailed, message.into()));
+
+    match details {
+        api::ContentDetails::Image(_) if !media.content_type.starts_with("image/") => {
+            return Err(mismatch("Image content has to refer to an uploaded image."));
+        }
+        api::ContentDetails::Audio(audio) => {
+            if !media::AUDIO_CONTENT_TYPES.contains(&media.content_type.as_str()) {
+                return Err(mismatch("Audio content has to refer to uploaded audio."));
+            }
+            audio.duration_ms = media.probe.duration_ms;
+            audio.codec = media.probe.codec;
+        }
+        api::ContentDetails::Video(video) => {
+            if !media::VIDEO_CONTENT_TYPES.contains(&media.content_type.as_str()) {
+                return Err(mismatch("Video content has to refer to uploaded video."));
+            }
+            if let Some(poster_uuid) = video.poster {
+                let poster = db.get_media_by_uuid(user, poster_uuid).await?;
+                if !poster.content_type.starts_with("image/") {
+                    return Err(mismatch("A video's poster has to be an uploaded image."));
+                }
+            }
+            video.duration_ms = media.probe.duration_ms;
+            video.width = media.probe.width;
+            video.height = media.probe.height;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn check_version(story: &model::Story, expected_version: Option<u32>) -> Result<(), AppError> {
+    match expected_version {
+        Some(version) if version != story.version => Err(AppError(
+            ErrorCode::PreconditionFailed,
+            format!(
+                "Story has been modified, the current version is {}.",
+                story.version
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub enum ContentOperation {
+    Update {
+        uuid: Uuid,
+        content: api::ContentDetails,
+    },
+    Add {
+        content: api::ContentDetails,
+        position: Option<u32>,
+    },
+    Remove {
+        uuid: Uuid,
+    },
+    Move {
+        uuid: Uuid,
+        position: u32,
+    },
+}
+
+/// A content block in the story's desired order while operations are being applied.
+enum Block {
+    Existing {
+        content: model::Content,
+        update: Option<api::ContentDetails>,
+    },
+    New(api::ContentDetails),
+}
+
+impl Block {
+    fn is(&self, uuid: Uuid) -> bool {
+        match self {
+            Block::Existing { content, .. } => content.uuid == uuid,
+            Block::New(_) => false,
+        }
+    }
+}
+
+/// Applies content operations to the story's current content, then writes the resulting order.
+/// Operations can only reference content that belongs to `story`.
+async fn update_content<A>(
+    db: &A,
+    user: &VerifiedUser,
     story: &model::Story,
-    updates: Vec<ContentUpdate>,
+    operations: Vec<ContentOperation>,
 ) -> Result<(), AppError>
 where
-    A: access::story::AccessStory,
+    A: access::story::AccessStory + AccessMedia,
 {
     if story.deleted {
         return Err(AppError(
-            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
             "Story has been deleted.".into(),
         ));
     }
 
-    for u in updates {
-        let content_id = db.get_content_by_uuid(u.uuid).await?.id;
-        db.update_content(content_id, u.content).await?;
+    if operations.is_empty() {
+        return Ok(());
+    }
+
+    let mut blocks: Vec<Block> = db
+        .get_story_content(user, story.id)
+        .await?
+        .into_iter()
+        .map(|content| Block::Existing {
+            content,
+            update: None,
+        })
+        .collect();
+    let mut removed = Vec::new();
+
+    let not_found = || AppError(ErrorCode::NotFound, "Content not found.".into());
+
+    for op in operations {
+        match op {
+            ContentOperation::Update { uuid, mut content } => {
+                resolve_media(db, user, &mut content).await?;
+                match blocks.iter_mut().find(|b| b.is(uuid)) {
+                    Some(Block::Existing { update, .. }) => *update = Some(content),
+                    _ => return Err(not_found()),
+                }
+            }
+            ContentOperation::Add {
+                mut content,
+                position,
+            } => {
+                resolve_media(db, user, &mut content).await?;
+                let index = position.map_or(blocks.len(), |p| (p as usize).min(blocks.len()));
+                blocks.insert(index, Block::New(content));
+            }
+            ContentOperation::Remove { uuid } => {
+                let index = blocks
+                    .iter()
+                    .position(|b| b.is(uuid))
+                    .ok_or_else(not_found)?;
+                if let Block::Existing { content, .. } = blocks.remove(index) {
+                    removed.push(content.id);
+                }
+            }
+            ContentOperation::Move { uuid, position } => {
+                let index = blocks
+                    .iter()
+                    .position(|b| b.is(uuid))
+                    .ok_or_else(not_found)?;
+                let block = blocks.remove(index);
+                blocks.insert((position as usize).min(blocks.len()), block);
+            }
+        }
+    }
+
+    // Each block was validated on its own, the total is only known once the operations are applied.
+    if blocks.len() > validation::MAX_CONTENT_BLOCKS {
+        return Err(AppError(
+            ErrorCode::ValidationFailed,
+            format!(
+                "A story can have at most {} content blocks.",
+                validation::MAX_CONTENT_BLOCKS
+            ),
+        ));
+    }
+
+    for content_id in removed {
+        db.delete_content(user, content_id).await?;
+    }
+
+    for (position, block) in blocks.into_iter().enumerate() {
+        let position = position as u32;
+        match block {
+            Block::Existing { content, update } => {
+                if let Some(details) = update {
+                    db.update_content(user, content.id, details).await?;
+                }
+                if content.position != position {
+                    db.set_content_position(user, content.id, position).await?;
+                }
+            }
+            Block::New(details) => {
+                db.create_content(user, story.id, position, vec![details])
+                    .await?;
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Executor, MySqlPool};
+
+    use super::*;
+    use crate::access::{user::AccessUser, MemoryDb};
+
+    /// Makes inserting text content with the body `fail` error, standing in for a failure
+    /// partway through a story's writes.
+    const FAIL_CONTENT_TRIGGER: &str = "CREATE TRIGGER fail_content_insert BEFORE INSERT ON content FOR EACH ROW BEGIN IF JSON_UNQUOTE(JSON_EXTRACT(NEW.details, '$.body')) = 'fail' THEN SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'injected failure'; END IF; END";
+
+    async fn setup(pool: MySqlPool) -> (MemoryDb, VerifiedUser) {
+        pool.execute(FAIL_CONTENT_TRIGGER).await.unwrap();
+
+        let db = MemoryDb::new(pool);
+        let user = db.create_user(Uuid::new_v4(), "test".into()).await.unwrap();
+        (db, VerifiedUser::new(user))
+    }
+
+    fn text(body: &str) -> api::ContentDetails {
+        api::ContentDetails::Text(api::TextContent {
+            title: String::new(),
+            body: body.into(),
+        })
+    }
+
+    async fn count_rows(pool: &MySqlPool) -> (i64, i64, i64) {
+        let stories = sqlx::query_scalar!("SELECT COUNT(*) FROM stories")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let content = sqlx::query_scalar!("SELECT COUNT(*) FROM content")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let revisions = sqlx::query_scalar!("SELECT COUNT(*) FROM story_revisions")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        (stories, content, revisions)
+    }
+
+    #[sqlx::test]
+    async fn create_story_rolls_back_when_a_content_insert_fails(pool: MySqlPool) {
+        let (db, user) = setup(pool.clone()).await;
+
+        let result = create_story(
+            &db,
+            &user,
+            "Title".into(),
+            None,
+            vec![text("first"), text("second"), text("fail")],
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(count_rows(&pool).await, (0, 0, 0));
+    }
+
+    #[sqlx::test]
+    async fn update_story_rolls_back_when_a_content_insert_fails(pool: MySqlPool) {
+        let (db, user) = setup(pool.clone()).await;
+        let story = create_story(&db, &user, "Title".into(), None, vec![text("first")])
+            .await
+            .unwrap();
+
+        let result = update_story(
+            &db,
+            &user,
+            story.uuid,
+            Some(story.version),
+            Some("Changed".into()),
+            None,
+            vec![
+                ContentOperation::Add {
+                    content: text("second"),
+                    position: None,
+                },
+                ContentOperation::Add {
+                    content: text("fail"),
+                    position: None,
+                },
+            ],
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(count_rows(&pool).await, (1, 1, 1));
+
+        let unchanged = get_story(&db, &user, story.uuid).await.unwrap();
+        assert_eq!(unchanged.title, "Title");
+        assert_eq!(unchanged.version, story.version);
+        assert_eq!(unchanged.content.len(), 1);
+    }
+}
@@ -0,0 +1,170 @@
+use uuid::Uuid;
+
+use crate::{
+    access::{media::AccessMedia, revision::AccessRevision, story::AccessStory, Transactional},
+    api,
+    auth::VerifiedUser,
+    model, AppError,
+};
+
+use super::{check_version, story_response, update_content, ActionError, ContentOperation};
+
+/// Returns the story's revisions, newest first.
+pub async fn list_revisions<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+) -> Result<Vec<api::RevisionSummary>, ActionError>
+where
+    A: AccessStory + AccessRevision,
+{
+    let story = db.get_story_by_uuid(user, story_uuid).await?;
+    let revisions = db.get_story_revisions(story.id).await?;
+
+    Ok(revisions.into_iter().map(|r| r.into()).collect())
+}
+
+/// Returns a revision of the story along with its diff against the previous revision.
+pub async fn get_revision<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+    version: u32,
+) -> Result<api::Revision, ActionError>
+where
+    A: AccessStory + AccessRevision,
+{
+    let story = db.get_story_by_uuid(user, story_uuid).await?;
+    let revision = db.get_revision(story.id, version).await?;
+    let previous = db.get_previous_revision(story.id, version).await?;
+
+    let diff = diff_revisions(previous.as_ref(), &revision);
+
+    Ok(api::Revision {
+        version: revision.version,
+        title: revision.title,
+        content: revision.content.into_iter().map(|c| c.into()).collect(),
+        location: revision.location.map(Into::into),
+        created_at: revision.created_at,
+        diff,
+    })
+}
+
+/// Rolls the story back to the title, location and content of one of its revisions, as a new version.
+/// Content that has since been removed is added back with a new uuid.
+pub async fn restore_revision<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+    version: u32,
+    expected_version: Option<u32>,
+) -> Result<api::Story, AppError>
+where
+    A: AccessStory + AccessRevision + AccessMedia + Transactional,
+{
+    let tx = db.begin().await?;
+
+    let mut story = tx.get_story_by_uuid(user, story_uuid).await?;
+    check_version(&story, expected_version)?;
+
+    let revision = tx.get_revision(story.id, version).await?;
+    let current = tx.get_story_content(user, story.id).await?;
+
+    // Drop whatever isn't in the revision, then lay the revision's blocks out in order.
+    let mut operations: Vec<ContentOperation> = current
+        .iter()
+        .filter(|c| !revision.content.iter().any(|r| r.uuid == c.uuid))
+        .map(|c| ContentOperation::Remove { uuid: c.uuid })
+        .collect();
+    for (position, block) in revision.content.into_iter().enumerate() {
+        let position = position as u32;
+        match current.iter().any(|c| c.uuid == block.uuid) {
+            true => {
+                operations.push(ContentOperation::Update {
+                    uuid: block.uuid,
+                    content: block.details.into(),
+                });
+                operations.push(ContentOperation::Move {
+                    uuid: block.uuid,
+                    position,
+                });
+            }
+            false => operations.push(ContentOperation::Add {
+                content: block.details.into(),
+                position: Some(position),
+            }),
+        }
+    }
+
+    story.title = revision.title;
+    let story = tx.update_story(user, story).await?;
+    tx.set_story_location(story.id, revision.location).await?;
+
+    update_content(&tx, user, &story, operations).await?;
+
+    let content = tx.get_story_content(user, story.id).await?;
+    tx.create_revision(&story, &content).await?;
+
+    tx.commit().await?;
+
+    Ok(story_response(db, user, story, content).await?)
+}
+
+fn diff_revisions(
+    previous: Option<&model::Revision>,
+    revision: &model::Revision,
+) -> api::RevisionDiff {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => {
+            return api::RevisionDiff {
+                added: revision.content.iter().map(|c| c.uuid).collect(),
+                ..Default::default()
+            }
+        }
+    };
+
+    let mut diff = api::RevisionDiff {
+        previous_version: Some(previous.version),
+        title_changed: previous.title != revision.title,
+        location_changed: previous.location != revision.location,
+        ..Default::default()
+    };
+
+    // Blocks present in both revisions, in each revision's order, to find the ones that moved.
+    let kept: Vec<Uuid> = revision
+        .content
+        .iter()
+        .map(|c| c.uuid)
+        .filter(|uuid| previous.content.iter().any(|p| p.uuid == *uuid))
+        .collect();
+    let previously_kept: Vec<Uuid> = previous
+        .content
+        .iter()
+        .map(|c| c.uuid)
+        .filter(|uuid| kept.contains(uuid))
+        .collect();
+
+    for block in revision.content.iter() {
+        match previous.content.iter().find(|p| p.uuid == block.uuid) {
+            Some(before) => {
+                if before.details != block.details {
+                    diff.changed.push(block.uuid);
+                }
+            }
+            None => diff.added.push(block.uuid),
+        }
+    }
+    for block in previous.content.iter() {
+        if !kept.contains(&block.uuid) {
+            diff.removed.push(block.uuid);
+        }
+    }
+    for (index, uuid) in kept.iter().enumerate() {
+        if previously_kept[index] != *uuid {
+            diff.moved.push(*uuid);
+        }
+    }
+
+    diff
+}
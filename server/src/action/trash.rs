@@ -0,0 +1,118 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::{
+    access::{media::AccessMedia, story::AccessStory, Transactional},
+    api,
+    auth::VerifiedUser,
+    blob::BlobStore,
+};
+
+use super::{media::collect_media, story_response, ActionError};
+
+/// How often the trash is checked for stories past their retention period.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Returns the user's stories in the trash along with their content.
+pub async fn list_trash<A>(db: &A, user: &VerifiedUser) -> Result<Vec<api::Story>, ActionError>
+where
+    A: AccessStory + AccessMedia,
+{
+    let mut stories = Vec::new();
+    for story in db.list_deleted_stories(user).await? {
+        let content = db.get_story_content(user, story.id).await?;
+        stories.push(story_response(db, user, story, content).await?);
+    }
+
+    Ok(stories)
+}
+
+/// Moves a story out of the trash.
+pub async fn restore_story<A>(
+    db: &A,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+) -> Result<api::Story, ActionError>
+where
+    A: AccessStory + AccessMedia,
+{
+    let mut story = db.get_deleted_story_by_uuid(user, story_uuid).await?;
+    story.deleted = false;
+
+    let story = db.update_story(user, story).await?;
+    let content = db.get_story_content(user, story.id).await?;
+
+    Ok(story_response(db, user, story, content).await?)
+}
+
+/// Permanently deletes a story in the trash. Stories must be trashed before they can be purged.
+/// Media only the story referred to is deleted along with it.
+pub async fn purge_story<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+) -> Result<(), ActionError>
+where
+    A: AccessStory + AccessMedia + Transactional,
+{
+    let tx = db.begin().await?;
+
+    let story = tx.get_deleted_story_by_uuid(user, story_uuid).await?;
+    let media_ids = tx.purge_story(story.id).await?;
+
+    tx.commit().await?;
+
+    collect_media(db, blobs, media_ids).await;
+
+    Ok(())
+}
+
+/// Permanently deletes every story that has been in the trash for longer than `retention`.
+///
+/// Returns the number of stories purged.
+pub async fn purge_expired_stories<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    retention: Duration,
+) -> Result<usize, ActionError>
+where
+    A: AccessStory + AccessMedia + Transactional,
+{
+    let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::max_value());
+    let deleted_before = Utc::now()
+        .checked_sub_signed(retention)
+        .unwrap_or(chrono::DateTime::<Utc>::MIN_UTC);
+
+    let story_ids = db.get_expired_story_ids(deleted_before).await?;
+    for story_id in story_ids.iter() {
+        let tx = db.begin().await?;
+        let media_ids = tx.purge_story(*story_id).await?;
+        tx.commit().await?;
+
+        collect_media(db, blobs, media_ids).await;
+    }
+
+    Ok(story_ids.len())
+}
+
+/// Runs [purge_expired_stories] on an interval, forever. Intended to be spawned at startup.
+pub async fn purge_periodically<A>(db: A, blobs: Arc<dyn BlobStore>, retention: Duration)
+where
+    A: AccessStory + AccessMedia + Transactional + Send + Sync,
+{
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match purge_expired_stories(&db, blobs.as_ref(), retention).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} stories from the trash", purged),
+            Err(ActionError::AccessError(err)) => {
+                tracing::error!(error = ?err, "failed to purge the trash")
+            }
+        }
+    }
+}
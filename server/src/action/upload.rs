@@ -0,0 +1,322 @@
+use std::{io, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use uuid::Uuid;
+
+use crate::{
+    access::{media::AccessMedia, upload::AccessUpload, AccessError, Transactional},
+    api,
+    auth::VerifiedUser,
+    blob::{BlobError, BlobStore, BlobStream},
+    error::ErrorCode,
+    model, AppError,
+};
+
+use super::media::{self, VariantQueue};
+
+/// How often expired uploads are cleaned up.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Limits on resumable uploads.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadConfig {
+    /// Bytes of unfinished uploads a user may have at once, by their declared size.
+    pub quota: u64,
+    /// Bytes of media a user may store, counting unfinished uploads by their declared size.
+    pub storage_quota: u64,
+    /// An upload is discarded after going this long without receiving a chunk.
+    pub expiry: Duration,
+}
+
+impl UploadConfig {
+    fn expires_at(&self) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::from_std(self.expiry).unwrap_or(chrono::Duration::zero())
+    }
+}
+
+/// Starts a resumable upload of `size` bytes, to be checked against `sha256` once finished.
+/// Fails if the user's unfinished uploads would exceed their quota,
+/// or if finishing them would take the user's media past their storage quota.
+pub async fn create_upload<A>(
+    db: &A,
+    config: &UploadConfig,
+    user: &VerifiedUser,
+    content_type: String,
+    size: u64,
+    sha256: String,
+    keep_metadata: bool,
+) -> Result<api::Upload, AppError>
+where
+    A: AccessUpload + AccessMedia,
+{
+    let user_id = user.id()?;
+
+    let pending = db.get_pending_upload_size(user_id).await?;
+    if pending.saturating_add(size) > config.quota {
+        return Err(AppError(
+            ErrorCode::PayloadTooLarge,
+            format!(
+                "Unfinished uploads are limited to {} bytes, {} are already in use.",
+                config.quota, pending
+            ),
+        ));
+    }
+    media::check_storage_quota(db, config, user, pending.saturating_add(size)).await?;
+
+    let upload = db
+        .create_upload(
+            user_id,
+            content_type,
+            size,
+            sha256.to_ascii_lowercase(),
+            keep_metadata,
+            config.expires_at(),
+        )
+        .await?;
+
+    Ok(upload.into())
+}
+
+/// Returns the user's upload, the client resumes from its `offset`.
+pub async fn get_upload<A>(
+    db: &A,
+    user: &VerifiedUser,
+    upload_uuid: Uuid,
+) -> Result<api::Upload, AppError>
+where
+    A: AccessUpload,
+{
+    Ok(db.get_upload_by_uuid(user, upload_uuid).await?.into())
+}
+
+/// Stores a chunk of the upload, which has to start at the upload's current offset.
+/// A chunk that fails midway is discarded as a whole, the client resumes from the returned offset.
+pub async fn append_upload<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    config: &UploadConfig,
+    user: &VerifiedUser,
+    upload_uuid: Uuid,
+    offset: u64,
+    body: BlobStream<'_>,
+) -> Result<api::Upload, AppError>
+where
+    A: AccessUpload + Transactional,
+{
+    let upload = db.get_upload_by_uuid(user, upload_uuid).await?;
+    if offset != upload.received {
+        return Err(offset_conflict(&upload));
+    }
+
+    // Stop reading as soon as the chunk runs past the declared size.
+    let remaining = upload.size - upload.received;
+    let mut read = 0;
+    let body = body
+        .and_then(move |chunk| {
+            read += chunk.len() as u64;
+            future::ready(match read > remaining {
+                true => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk exceeds the upload's size",
+                )),
+                false => Ok(chunk),
+            })
+        })
+        .boxed();
+
+    // Every attempt gets its own key, so concurrent attempts at the same offset can't clobber each other.
+    let storage_key = format!("upload_{}", Uuid::new_v4());
+    let size = match blobs.put(&storage_key, body).await {
+        Ok(size) => size,
+        Err(BlobError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {
+            return Err(AppError(
+                ErrorCode::PayloadTooLarge,
+                format!(
+                    "Chunk extends past the upload's size of {} bytes.",
+                    upload.size
+                ),
+            ));
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if size == 0 {
+        blobs.delete(&storage_key).await?;
+        return Err(AppError(ErrorCode::BadRequest, "Chunk is empty.".into()));
+    }
+
+    let appended = append_chunk(
+        db,
+        upload.id,
+        offset,
+        size,
+        &storage_key,
+        config.expires_at(),
+    )
+    .await;
+    match appended {
+        Ok(true) => {}
+        Ok(false) => {
+            blobs.delete(&storage_key).await?;
+            let upload = db.get_upload_by_uuid(user, upload_uuid).await?;
+            return Err(offset_conflict(&upload));
+        }
+        Err(err) => {
+            blobs.delete(&storage_key).await?;
+            return Err(err.into());
+        }
+    }
+
+    get_upload(db, user, upload_uuid).await
+}
+
+async fn append_chunk<A>(
+    db: &A,
+    upload_id: u32,
+    offset: u64,
+    size: u64,
+    storage_key: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<bool, AccessError>
+where
+    A: AccessUpload + Transactional,
+{
+    let tx = db.begin().await?;
+    let appended = tx
+        .append_upload_chunk(upload_id, offset, size, storage_key.into(), expires_at)
+        .await?;
+    tx.commit().await?;
+
+    Ok(appended)
+}
+
+fn offset_conflict(upload: &model::Upload) -> AppError {
+    AppError(
+        ErrorCode::Conflict,
+        format!("Upload is at offset {}.", upload.received),
+    )
+}
+
+/// Assembles a complete upload into media, see [media::upload_media].
+/// The upload is discarded once it has become media, or if it's invalid or too large to store,
+/// which resuming can't fix. Other failures leave it in place to be finalized again.
+pub async fn finalize_upload<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    variants: &VariantQueue,
+    config: &UploadConfig,
+    user: &VerifiedUser,
+    upload_uuid: Uuid,
+) -> Result<api::Media, AppError>
+where
+    A: AccessUpload + AccessMedia + Transactional,
+{
+    let upload = db.get_upload_by_uuid(user, upload_uuid).await?;
+    if upload.received != upload.size {
+        return Err(AppError(
+            ErrorCode::Conflict,
+            format!(
+                "Upload is incomplete, {} of {} bytes have been received.",
+                upload.received, upload.size
+            ),
+        ));
+    }
+
+    let chunks = db.get_upload_chunks(upload.id).await?;
+    let body =
+        stream::iter(chunks)
+            .then(move |storage_key| async move {
+                blobs.get(&storage_key).await.map_err(io::Error::from)
+            })
+            .try_flatten()
+            .boxed();
+
+    let result = media::upload_media(
+        db,
+        blobs,
+        variants,
+        config,
+        user,
+        upload.content_type.clone(),
+        upload.keep_metadata,
+        Some(&upload.sha256),
+        body,
+    )
+    .await;
+
+    match &result {
+        Ok(_)
+        | Err(AppError(ErrorCode::ValidationFailed, _))
+        | Err(AppError(ErrorCode::PayloadTooLarge, _)) => {
+            discard_upload(db, blobs, upload.id).await?
+        }
+        Err(_) => {}
+    }
+
+    result
+}
+
+/// Abandons an unfinished upload, freeing up its share of the quota.
+pub async fn cancel_upload<A>(
+    db: &A,
+    blobs: &dyn BlobStore,
+    user: &VerifiedUser,
+    upload_uuid: Uuid,
+) -> Result<(), AppError>
+where
+    A: AccessUpload + Transactional,
+{
+    let upload = db.get_upload_by_uuid(user, upload_uuid).await?;
+    discard_upload(db, blobs, upload.id).await?;
+
+    Ok(())
+}
+
+/// Deletes the upload, then its chunks' blobs. A blob that can't be deleted is only logged.
+async fn discard_upload<A>(db: &A, blobs: &dyn BlobStore, upload_id: u32) -> Result<(), AccessError>
+where
+    A: AccessUpload + Transactional,
+{
+    let tx = db.begin().await?;
+    let storage_keys = tx.delete_upload(upload_id).await?;
+    tx.commit().await?;
+
+    for storage_key in storage_keys {
+        if let Err(err) = blobs.delete(&storage_key).await {
+            tracing::error!(storage_key, error = ?err, "failed to delete upload chunk");
+        }
+    }
+
+    Ok(())
+}
+
+/// Discards every user's expired uploads, returning how many there were.
+pub async fn expire_uploads<A>(db: &A, blobs: &dyn BlobStore) -> Result<usize, AccessError>
+where
+    A: AccessUpload + Transactional,
+{
+    let upload_ids = db.get_expired_upload_ids().await?;
+    for upload_id in upload_ids.iter() {
+        discard_upload(db, blobs, *upload_id).await?;
+    }
+
+    Ok(upload_ids.len())
+}
+
+/// Runs [expire_uploads] on an interval, forever. Intended to be spawned at startup.
+pub async fn expire_uploads_periodically<A>(db: A, blobs: Arc<dyn BlobStore>)
+where
+    A: AccessUpload + Transactional + Send + Sync,
+{
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match expire_uploads(&db, blobs.as_ref()).await {
+            Ok(0) => {}
+            Ok(expired) => tracing::info!("Discarded {} expired uploads", expired),
+            Err(err) => tracing::error!(error = ?err, "failed to discard expired uploads"),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Version written as `v` alongside every content's details.
+pub const CONTENT_DETAILS_VERSION: u32 = 1;
+
+fn current_version() -> u32 {
+    CONTENT_DETAILS_VERSION
+}
+
+/// Content details tagged with their `type`, shared by the api and model representations.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaggedContentDetails<I, T, A, V, L> {
+    Image(I),
+    Text(T),
+    Audio(A),
+    Video(V),
+    Location(L),
+}
+
+/// The format content details are sent and stored in,
+/// e.g. `{"type": "text", "v": 1, "title": "...", "body": "..."}`.
+#[derive(Serialize, Deserialize)]
+pub struct VersionedContentDetails<I, T, A, V, L> {
+    #[serde(default = "current_version")]
+    pub v: u32,
+    #[serde(flatten)]
+    pub details: TaggedContentDetails<I, T, A, V, L>,
+}
+
+impl<I, T, A, V, L> From<TaggedContentDetails<I, T, A, V, L>>
+    for VersionedContentDetails<I, T, A, V, L>
+{
+    fn from(details: TaggedContentDetails<I, T, A, V, L>) -> Self {
+        Self {
+            v: CONTENT_DETAILS_VERSION,
+            details,
+        }
+    }
+}
+
+/// Every format content details can be read from.
+/// Details written before they were tagged have their type inferred from their fields,
+/// types added since then are always tagged.
+pub enum ContentDetailsFormat<I, T, A, V, L> {
+    Versioned(VersionedContentDetails<I, T, A, V, L>),
+    LegacyImage(I),
+    LegacyText(T),
+}
+
+/// Only details without a `type` are read as a legacy format, so a tagged block that doesn't
+/// match its type is rejected rather than read as an image or text.
+/// Unknown types, and versions newer than [CONTENT_DETAILS_VERSION], are rejected too.
+impl<'de, I, T, A, V, L> Deserialize<'de> for ContentDetailsFormat<I, T, A, V, L>
+where
+    I: DeserializeOwned,
+    T: DeserializeOwned,
+    A: DeserializeOwned,
+    V: DeserializeOwned,
+    L: DeserializeOwned,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = Value::deserialize(deserializer)?;
+        let fields = value
+            .as_object()
+            .ok_or_else(|| de::Error::custom("content details must be an object"))?;
+
+        if !fields.contains_key("type") {
+            if fields.contains_key("v") {
+                return Err(de::Error::missing_field("type"));
+            }
+            // Legacy images always had a `src`, legacy text never did.
+            return if fields.contains_key("src") {
+                I::deserialize(value)
+                    .map(Self::LegacyImage)
+                    .map_err(de::Error::custom)
+            } else {
+                T::deserialize(value)
+                    .map(Self::LegacyText)
+                    .map_err(de::Error::custom)
+            };
+        }
+
+        let versioned = VersionedContentDetails::<I, T, A, V, L>::deserialize(value)
+            .map_err(de::Error::custom)?;
+        if versioned.v == 0 || versioned.v > CONTENT_DETAILS_VERSION {
+            return Err(de::Error::custom(format!(
+                "unsupported content details version {}",
+                versioned.v
+            )));
+        }
+
+        Ok(Self::Versioned(versioned))
+    }
+}
+
+impl<I, T, A, V, L> From<ContentDetailsFormat<I, T, A, V, L>>
+    for TaggedContentDetails<I, T, A, V, L>
+{
+    fn from(format: ContentDetailsFormat<I, T, A, V, L>) -> Self {
+        match format {
+            ContentDetailsFormat::Versioned(versioned) => versioned.details,
+            ContentDetailsFormat::LegacyImage(image) => TaggedContentDetails::Image(image),
+            ContentDetailsFormat::LegacyText(text) => TaggedContentDetails::Text(text),
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::model;
 
+mod content_format;
+pub use content_format::*;
+
 #[derive(Debug)]
 pub enum ApiError {
     Encode,
@@ -18,6 +21,35 @@ pub struct User {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub uuid: Uuid,
+    pub name: String,
+    pub scope: model::Scope,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Credential {
+    pub uuid: Uuid,
+    pub name: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub uuid: Uuid,
+    pub device_label: Option<String>,
+    pub ip: Option<String>,
+    pub current: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Story {
     pub uuid: Uuid,
@@ -25,6 +57,13 @@ pub struct Story {
     pub content: Vec<Content>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub version: u32,
+    /// Only set for stories in the trash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Where the story took place, filled in on responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<StoryLocation>,
 }
 
 impl Story {
@@ -36,10 +75,202 @@ impl Story {
             content,
             created_at: story.created_at,
             updated_at: story.updated_at,
+            version: story.version,
+            deleted_at: story.deleted_at,
+            location: None,
         }
     }
 }
 
+/// A story's primary location, placing it on the map.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoryLocation {
+    /// WGS 84 coordinates, in degrees.
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place_name: Option<String>,
+}
+
+impl Into<model::StoryLocation> for StoryLocation {
+    fn into(self) -> model::StoryLocation {
+        model::StoryLocation {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            place_name: self.place_name,
+        }
+    }
+}
+
+/// The user's stories within an area of the map.
+/// Zoomed out, stories close together are grouped into clusters and only the rest are listed.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryMap {
+    pub stories: Vec<StoryPin>,
+    pub clusters: Vec<StoryCluster>,
+    /// Set when the area holds more stories than are returned, zooming in shows the rest.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryPin {
+    pub uuid: Uuid,
+    pub title: String,
+    pub location: StoryLocation,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryCluster {
+    pub count: u32,
+    /// Average position of the clustered stories.
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Smallest area holding every clustered story, for zooming in on the cluster.
+    pub bbox: BoundingBox,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+/// A page of stories. Pass `next_cursor` back to fetch the following page.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoryPage {
+    pub stories: Vec<Story>,
+    pub next_cursor: Option<String>,
+}
+
+/// Position in a list of stories ordered by `created_at` and then `id`, both descending.
+/// Handed to clients as an opaque string.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoryCursor {
+    pub created_at: DateTime<Utc>,
+    pub id: u32,
+}
+
+impl StoryCursor {
+    pub fn encode(&self) -> String {
+        hex::encode(format!(
+            "{}:{}",
+            self.created_at.timestamp_millis(),
+            self.id
+        ))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, ApiError> {
+        let decoded = hex::decode(cursor).map_err(|_| ApiError::Decode)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| ApiError::Decode)?;
+        let (millis, id) = decoded.split_once(':').ok_or(ApiError::Decode)?;
+
+        let millis = millis.parse::<i64>().map_err(|_| ApiError::Decode)?;
+        let created_at = Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .ok_or(ApiError::Decode)?;
+        let id = id.parse::<u32>().map_err(|_| ApiError::Decode)?;
+
+        Ok(Self { created_at, id })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Media {
+    pub uuid: Uuid,
+    pub content_type: String,
+    pub size: u64,
+    /// Where the media can be downloaded from.
+    pub url: String,
+    /// When the photo was taken, in the camera's local time.
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Length and codec of audio and video.
+    pub duration_ms: Option<u64>,
+    pub codec: Option<String>,
+    /// Dimensions of video.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Where a frame extracted from video can be downloaded from, once it has been extracted.
+    pub poster_url: Option<String>,
+    /// Hex encoded SHA-256 of the file, can be sent with an upload to skip uploading it again.
+    pub sha256: Option<String>,
+    /// Number of content blocks using the media.
+    pub references: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Media {
+    pub fn url(media_uuid: Uuid) -> String {
+        format!("/media/{}", media_uuid)
+    }
+
+    pub fn poster_url(media_uuid: Uuid) -> String {
+        format!("/media/{}/poster", media_uuid)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaVariant {
+    pub size: model::VariantSize,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Upload {
+    pub uuid: Uuid,
+    pub content_type: String,
+    pub size: u64,
+    /// Bytes received so far, the next chunk has to be sent from here.
+    pub offset: u64,
+    /// Unfinished uploads are discarded after this, it's pushed back whenever a chunk is received.
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevisionSummary {
+    pub version: u32,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A story as it was at `version`, along with what changed since the revision before it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    pub version: u32,
+    pub title: String,
+    pub content: Vec<RevisionContent>,
+    pub location: Option<StoryLocation>,
+    pub created_at: DateTime<Utc>,
+    pub diff: RevisionDiff,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevisionContent {
+    pub uuid: Uuid,
+    pub details: ContentDetails,
+}
+
+/// Changes between a revision and the one before it, content is referenced by uuid.
+/// The first revision's diff lists all of its content as added.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RevisionDiff {
+    pub previous_version: Option<u32>,
+    pub title_changed: bool,
+    pub location_changed: bool,
+    pub added: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+    pub changed: Vec<Uuid>,
+    pub moved: Vec<Uuid>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Content {
     pub uuid: Uuid,
@@ -50,17 +281,89 @@ pub struct Content {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-#[serde(rename_all = "snake_case")]
+#[serde(
+    from = "ContentDetailsFormat<ImageContent, TextContent, AudioContent, VideoContent, LocationContent>",
+    into = "VersionedContentDetails<ImageContent, TextContent, AudioContent, VideoContent, LocationContent>"
+)]
 pub enum ContentDetails {
     Image(ImageContent),
     Text(TextContent),
+    Audio(AudioContent),
+    Video(VideoContent),
+    Location(LocationContent),
+}
+
+impl
+    From<
+        ContentDetailsFormat<
+            ImageContent,
+            TextContent,
+            AudioContent,
+            VideoContent,
+            LocationContent,
+        >,
+    > for ContentDetails
+{
+    fn from(
+        format: ContentDetailsFormat<
+            ImageContent,
+            TextContent,
+            AudioContent,
+            VideoContent,
+            LocationContent,
+        >,
+    ) -> Self {
+        match format.into() {
+            TaggedContentDetails::Image(image) => ContentDetails::Image(image),
+            TaggedContentDetails::Text(text) => ContentDetails::Text(text),
+            TaggedContentDetails::Audio(audio) => ContentDetails::Audio(audio),
+            TaggedContentDetails::Video(video) => ContentDetails::Video(video),
+            TaggedContentDetails::Location(location) => ContentDetails::Location(location),
+        }
+    }
+}
+
+impl From<ContentDetails>
+    for VersionedContentDetails<
+        ImageContent,
+        TextContent,
+        AudioContent,
+        VideoContent,
+        LocationContent,
+    >
+{
+    fn from(details: ContentDetails) -> Self {
+        match details {
+            ContentDetails::Image(image) => TaggedContentDetails::Image(image).into(),
+            ContentDetails::Text(text) => TaggedContentDetails::Text(text).into(),
+            ContentDetails::Audio(audio) => TaggedContentDetails::Audio(audio).into(),
+            ContentDetails::Video(video) => TaggedContentDetails::Video(video).into(),
+            ContentDetails::Location(location) => TaggedContentDetails::Location(location).into(),
+        }
+    }
 }
 
 impl ContentDetails {
-    /// Returns the 'kind' [String] of the [ContentKind] variant.
+    /// Returns the `type` the details are tagged with, stored as the content's `kind`.
     pub fn kind(&self) -> String {
-        format!("{:?}", self).to_lowercase()
+        match self {
+            ContentDetails::Image(_) => "image".into(),
+            ContentDetails::Text(_) => "text".into(),
+            ContentDetails::Audio(_) => "audio".into(),
+            ContentDetails::Video(_) => "video".into(),
+            ContentDetails::Location(_) => "location".into(),
+        }
+    }
+
+    /// Returns the uploaded media the details refer to, if any.
+    pub fn media(&self) -> Option<Uuid> {
+        match self {
+            ContentDetails::Image(image) => image.media,
+            ContentDetails::Text(_) => None,
+            ContentDetails::Audio(audio) => Some(audio.media),
+            ContentDetails::Video(video) => Some(video.media),
+            ContentDetails::Location(_) => None,
+        }
     }
 
     pub fn details(&self) -> Result<String, ApiError> {
@@ -74,11 +377,33 @@ impl Into<model::ContentDetails> for ContentDetails {
             ContentDetails::Image(image) => model::ContentDetails::Image(model::ImageContent {
                 src: image.src,
                 description: image.description,
+                media: image.media,
             }),
             ContentDetails::Text(text) => model::ContentDetails::Text(model::TextContent {
                 title: text.title,
                 body: text.body,
             }),
+            ContentDetails::Audio(audio) => model::ContentDetails::Audio(model::AudioContent {
+                media: audio.media,
+                duration_ms: audio.duration_ms,
+                codec: audio.codec,
+                transcript: audio.transcript,
+            }),
+            ContentDetails::Video(video) => model::ContentDetails::Video(model::VideoContent {
+                media: video.media,
+                poster: video.poster,
+                duration_ms: video.duration_ms,
+                width: video.width,
+                height: video.height,
+            }),
+            ContentDetails::Location(location) => {
+                model::ContentDetails::Location(model::LocationContent {
+                    latitude: location.latitude,
+                    longitude: location.longitude,
+                    place_name: location.place_name,
+                    accuracy: location.accuracy,
+                })
+            }
         }
     }
 }
@@ -87,6 +412,19 @@ impl Into<model::ContentDetails> for ContentDetails {
 pub struct ImageContent {
     pub src: String,
     pub description: String,
+    /// Uploaded media the image refers to, see `POST /media`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<Uuid>,
+    /// Resized copies of `media`, filled in on responses once they have been generated.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<MediaVariant>,
+    /// When and where `media` was taken, filled in on responses from its EXIF.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<NaiveDateTime>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,6 +433,50 @@ pub struct TextContent {
     pub body: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioContent {
+    /// Uploaded audio the block plays, see `POST /media`.
+    pub media: Uuid,
+    /// Length and codec of `media`, read from the file when it was uploaded.
+    #[serde(default, skip_deserializing)]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_deserializing)]
+    pub codec: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoContent {
+    /// Uploaded video the block plays, see `POST /media`.
+    pub media: Uuid,
+    /// Uploaded image shown before the video plays.
+    /// Without one, a frame extracted from `media` is shown if the server could extract one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<Uuid>,
+    /// Length and dimensions of `media`, read from the file when it was uploaded.
+    #[serde(default, skip_deserializing)]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_deserializing)]
+    pub width: Option<u32>,
+    #[serde(default, skip_deserializing)]
+    pub height: Option<u32>,
+    /// Where the poster can be downloaded from, filled in on responses.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub poster_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocationContent {
+    /// WGS 84 coordinates, in degrees.
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: String,
+    /// Radius of uncertainty around the coordinates, in meters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<f64>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UpdateStoryRequest {
     pub title: Option<String>,
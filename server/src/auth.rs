@@ -1,61 +1,636 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
-use axum::http::StatusCode;
-use once_cell::sync::Lazy;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+};
+use chrono::{DateTime, Utc};
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
-use webauthn_rs::{prelude::Url, Webauthn, WebauthnBuilder};
+use webauthn_rs::{
+    prelude::{PasskeyAuthentication, PasskeyRegistration, Url, WebauthnError},
+    Webauthn, WebauthnBuilder,
+};
 
-use crate::{model, AppError, AppResult};
+use crate::{
+    access::{api_token::AccessApiToken, session::AccessSession, user::AccessUser},
+    error::ErrorCode,
+    model, AppContext, AppError, AppResult,
+};
 
-static AUTH_APP_ID: &'static str = "memory.io";
-static AUTH_APP_ORIGIN: Lazy<Url> = Lazy::new(|| Url::parse("https://memory.io").unwrap());
+/// How long a client has to complete a ceremony after starting it.
+const CEREMONY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Window failed recovery attempts are counted over.
+const RECOVERY_ATTEMPT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Failed recovery attempts a user can have within the window before recovery is locked.
+const MAX_RECOVERY_FAILURES_PER_USER: usize = 5;
+/// Failed recovery attempts an address can make within the window, across every user.
+const MAX_RECOVERY_FAILURES_PER_IP: usize = 20;
+
+/// Prefix that distinguishes personal API tokens from session tokens.
+pub const API_TOKEN_PREFIX: &'static str = "mem_pat_";
+
+/// Name of the cookie carrying the session token for clients that don't send `Authorization`.
+pub const SESSION_COOKIE: &'static str = "memory_session";
+
+/// Controls how long sessions stay valid.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    /// A session expires after going unused for this long.
+    pub idle_timeout: Duration,
+    /// A session expires this long after login, regardless of activity.
+    pub max_lifetime: Duration,
+}
+
+impl SessionConfig {
+    /// Returns the expiry of a session created at `created_at` that was just used.
+    /// Activity slides the expiry forward, but never past the maximum lifetime.
+    pub fn expires_at(&self, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        let idle_expiry = add_duration(Utc::now(), self.idle_timeout);
+        let absolute_expiry = add_duration(created_at, self.max_lifetime);
+
+        idle_expiry.min(absolute_expiry)
+    }
+}
+
+/// Durations are bounded when parsed, so this only saturates for ones chrono can't represent.
+fn add_duration(time: DateTime<Utc>, duration: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| time.checked_add_signed(duration))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
 
 #[derive(Clone)]
 pub struct AuthState {
     pub webauthn: Arc<Webauthn>,
-    verified_user: Option<VerifiedUser>,
+    pub registrations: CeremonyStore<PendingRegistration>,
+    pub authentications: CeremonyStore<PendingAuthentication>,
+    pub credential_registrations: CeremonyStore<PendingCredential>,
+    pub recoveries: CeremonyStore<PendingCredential>,
+    pub sessions: SessionConfig,
+    /// Reverse proxies trusted to report the client's address in `X-Forwarded-For`.
+    pub trusted_proxies: Arc<Vec<IpAddr>>,
+    pub recovery_failures_by_user: AttemptLimiter<u32>,
+    pub recovery_failures_by_ip: AttemptLimiter<String>,
 }
 
 impl AuthState {
-    /// Determines if a user is signed in.
-    /// If not, returns AppError(StatusCode::UNAUTHORIZED, "Login required")
-    pub fn authenticated(&self) -> AppResult<&VerifiedUser> {
-        self.verified_user
-            .as_ref()
-            .ok_or_else(|| AppError(StatusCode::UNAUTHORIZED, "Login required".into()))
+    /// Creates a new Webauthn client.
+    /// Returns an [AuthConfigError] if the relying party configuration is invalid.
+    pub fn new(
+        relying_party: RelyingPartyConfig,
+        sessions: SessionConfig,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> Result<Self, AuthConfigError> {
+        let webauthn = relying_party.build()?;
+
+        Ok(Self {
+            webauthn: Arc::new(webauthn),
+            registrations: CeremonyStore::new(),
+            authentications: CeremonyStore::new(),
+            credential_registrations: CeremonyStore::new(),
+            recoveries: CeremonyStore::new(),
+            sessions,
+            trusted_proxies: Arc::new(trusted_proxies),
+            recovery_failures_by_user: AttemptLimiter::new(
+                MAX_RECOVERY_FAILURES_PER_USER,
+                RECOVERY_ATTEMPT_WINDOW,
+            ),
+            recovery_failures_by_ip: AttemptLimiter::new(
+                MAX_RECOVERY_FAILURES_PER_IP,
+                RECOVERY_ATTEMPT_WINDOW,
+            ),
+        })
     }
+}
+
+/// Identifies this server to authenticators.
+#[derive(Debug, Clone)]
+pub struct RelyingPartyConfig {
+    /// The domain passkeys are bound to, e.g. `memory.io` or `localhost`.
+    pub id: String,
+    /// Human readable name shown by authenticators.
+    pub name: String,
+    /// Every origin ceremonies may be performed from, including the iOS associated domain.
+    pub origins: Vec<Url>,
+}
+
+impl RelyingPartyConfig {
+    fn build(self) -> Result<Webauthn, AuthConfigError> {
+        let (first, rest) = self
+            .origins
+            .split_first()
+            .ok_or(AuthConfigError::NoOrigins)?;
+
+        for origin in self.origins.iter() {
+            self.validate_origin(origin)?;
+        }
+
+        let mut builder = WebauthnBuilder::new(&self.id, first)
+            .map_err(AuthConfigError::Webauthn)?
+            .rp_name(&self.name);
+        for origin in rest {
+            builder = builder.append_allowed_origin(origin);
+        }
 
-    /// Sets the verified user.
-    pub fn set_user(&mut self, user: VerifiedUser) {
-        self.verified_user = Some(user)
+        builder.build().map_err(AuthConfigError::Webauthn)
+    }
+
+    /// An origin is only valid if it is served over http(s) from the relying party's domain
+    /// or one of its subdomains.
+    fn validate_origin(&self, origin: &Url) -> Result<(), AuthConfigError> {
+        let host = match origin.scheme() {
+            "https" | "http" => origin.host_str(),
+            _ => None,
+        }
+        .ok_or_else(|| AuthConfigError::InvalidOrigin(origin.clone()))?;
+
+        if host != self.id && !host.ends_with(&format!(".{}", self.id)) {
+            return Err(AuthConfigError::OriginMismatch {
+                origin: origin.clone(),
+                rp_id: self.id.clone(),
+            });
+        }
+
+        Ok(())
     }
 }
 
-impl AuthState {
-    /// Creates a new Webauthn client. Will panic if configuration is wrong.
+#[derive(Debug)]
+pub enum AuthConfigError {
+    NoOrigins,
+    InvalidOrigin(Url),
+    OriginMismatch { origin: Url, rp_id: String },
+    Webauthn(WebauthnError),
+}
+
+impl std::fmt::Display for AuthConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthConfigError::NoOrigins => {
+                write!(f, "at least one relying party origin is required")
+            }
+            AuthConfigError::InvalidOrigin(origin) => {
+                write!(f, "{} is not a valid http(s) origin", origin)
+            }
+            AuthConfigError::OriginMismatch { origin, rp_id } => write!(
+                f,
+                "{} is not served from the relying party id {} or one of its subdomains",
+                origin, rp_id
+            ),
+            AuthConfigError::Webauthn(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Generates a new opaque session token.
+/// The token is only ever handed to the client, see [hash_token] for what gets stored.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    hex::encode(bytes)
+}
+
+/// Returns the hex encoded SHA-256 digest of a token, which is what the database stores.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Characters recovery codes are made of. Omits characters that are easily confused, like `0` and `o`.
+const RECOVERY_CODE_ALPHABET: &'static [u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+/// How many recovery codes a user is given at a time.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generates a single-use recovery code formatted as `xxxx-xxxx-xxxx`.
+pub fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let groups: Vec<String> = (0..3)
+        .map(|_| {
+            (0..4)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect()
+        })
+        .collect();
+
+    groups.join("-")
+}
+
+/// Normalizes user input so codes can be typed without dashes or in upper case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Hashes a recovery code with argon2 for storage.
+pub fn hash_recovery_code(code: &str) -> AppResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(normalize_recovery_code(code).as_bytes(), &salt)
+        .map_err(|err| {
+            tracing::error!(error = ?err, "failed to hash recovery code");
+            AppError(ErrorCode::Internal, "Failed to hash recovery code.".into())
+        })?;
+
+    Ok(hash.to_string())
+}
+
+/// Checks a recovery code against a hash produced by [hash_recovery_code].
+pub fn verify_recovery_code(code: &str, code_hash: &str) -> bool {
+    match PasswordHash::new(code_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(normalize_recovery_code(code).as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Builds the `Set-Cookie` value that hands a session token to the client.
+/// The cookie outlives idle expiry, the server decides when the session is no longer valid.
+pub fn session_cookie(token: &str, config: &SessionConfig) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE,
+        token,
+        config.max_lifetime.as_secs()
+    )
+}
+
+/// Builds the `Set-Cookie` value that removes the session cookie from the client.
+pub fn clear_session_cookie() -> String {
+    format!(
+        "{}=; Max-Age=0; Path=/; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE
+    )
+}
+
+/// Reads the token from either `Authorization: Bearer <token>` or the session cookie.
+fn request_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return Some(token.trim().to_string());
+    }
+
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token.to_string())
+}
+
+impl From<WebauthnError> for AppError {
+    fn from(err: WebauthnError) -> Self {
+        tracing::debug!(error = ?err, "passkey ceremony failed");
+        AppError(ErrorCode::BadRequest, "Passkey ceremony failed.".into())
+    }
+}
+
+/// A registration ceremony that has been started but not yet finished.
+/// The user row isn't created until the authenticator's response has been verified.
+pub struct PendingRegistration {
+    pub user_uuid: Uuid,
+    pub name: String,
+    pub state: PasskeyRegistration,
+}
+
+/// Describes the client making a request, recorded against its sessions.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub device_label: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    fn from_parts(parts: &Parts, trusted_proxies: &[IpAddr]) -> Self {
+        let device_label = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(100).collect());
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let ip = match peer {
+            Some(peer) if trusted_proxies.contains(&peer) => {
+                Some(forwarded_for(&parts.headers, trusted_proxies).unwrap_or(peer))
+            }
+            _ => peer,
+        };
+
+        Self {
+            device_label,
+            ip: ip.map(|ip| ip.to_string()),
+        }
+    }
+}
+
+/// Reads the client's address from `X-Forwarded-For`, once the request came from a trusted proxy.
+/// Each proxy appends the address it received the request from, so the first untrusted address
+/// from the right is the client. Anything left of it was sent by the client and can't be trusted.
+fn forwarded_for(headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for ip in forwarded.into_iter().rev() {
+        let ip = ip.trim().parse::<IpAddr>().ok()?;
+        if !trusted_proxies.contains(&ip) {
+            return Some(ip);
+        }
+    }
+
+    None
+}
+
+#[async_trait]
+impl FromRequestParts<AppContext> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        ctx: &AppContext,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts, &ctx.auth.trusted_proxies))
+    }
+}
+
+/// A registration ceremony adding another passkey to an existing user.
+pub struct PendingCredential {
+    pub user_id: u32,
+    pub name: String,
+    pub state: PasskeyRegistration,
+}
+
+/// An authentication ceremony that has been started but not yet finished.
+pub struct PendingAuthentication {
+    pub user_id: u32,
+    pub state: PasskeyAuthentication,
+}
+
+/// Holds in-flight ceremony state server-side between the `start` and `finish` calls.
+/// Each ceremony is keyed by a random UUID that is handed to the client.
+pub struct CeremonyStore<T> {
+    inner: Arc<Mutex<HashMap<Uuid, (Instant, T)>>>,
+}
+
+impl<T> Clone for CeremonyStore<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> CeremonyStore<T> {
     pub fn new() -> Self {
-        let builder = WebauthnBuilder::new(AUTH_APP_ID, &AUTH_APP_ORIGIN).unwrap();
-        let builder = builder.rp_name("Memory");
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock_safe(&self) -> Result<MutexGuard<HashMap<Uuid, (Instant, T)>>, AppError> {
+        self.inner
+            .lock()
+            .map_err(|_| AppError(ErrorCode::Internal, "CeremonyStore was poisoned.".into()))
+    }
+
+    /// Stores the state of a newly started ceremony, dropping any that have expired.
+    ///
+    /// Returns the UUID the client must present to finish the ceremony.
+    pub fn insert(&self, state: T) -> AppResult<Uuid> {
+        let mut ceremonies = self.lock_safe()?;
+        ceremonies.retain(|_, (started_at, _)| started_at.elapsed() < CEREMONY_TIMEOUT);
+
+        let ceremony_uuid = Uuid::new_v4();
+        ceremonies.insert(ceremony_uuid, (Instant::now(), state));
+
+        Ok(ceremony_uuid)
+    }
+
+    /// Removes and returns the state of a ceremony. Each ceremony can only be finished once.
+    ///
+    /// Returns an AppError if the ceremony is unknown or has expired.
+    pub fn take(&self, ceremony_uuid: Uuid) -> AppResult<T> {
+        let mut ceremonies = self.lock_safe()?;
 
-        let webauthn = builder.build().unwrap();
+        match ceremonies.remove(&ceremony_uuid) {
+            Some((started_at, state)) if started_at.elapsed() < CEREMONY_TIMEOUT => Ok(state),
+            _ => Err(AppError(
+                ErrorCode::BadRequest,
+                "Unknown or expired ceremony.".into(),
+            )),
+        }
+    }
+}
+
+/// Counts failed attempts per key, e.g. a user or an address, and locks the key out once it has
+/// failed too often within the window. Failures are kept in memory, like ceremonies.
+pub struct AttemptLimiter<K> {
+    inner: Arc<Mutex<HashMap<K, Vec<Instant>>>>,
+    max_failures: usize,
+    window: Duration,
+}
 
+impl<K> Clone for AttemptLimiter<K> {
+    fn clone(&self) -> Self {
         Self {
-            webauthn: Arc::new(webauthn),
-            verified_user: None,
+            inner: self.inner.clone(),
+            max_failures: self.max_failures,
+            window: self.window,
+        }
+    }
+}
+
+impl<K: Hash + Eq> AttemptLimiter<K> {
+    pub fn new(max_failures: usize, window: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            max_failures,
+            window,
+        }
+    }
+
+    fn lock_safe(&self) -> Result<MutexGuard<HashMap<K, Vec<Instant>>>, AppError> {
+        self.inner
+            .lock()
+            .map_err(|_| AppError(ErrorCode::Internal, "AttemptLimiter was poisoned.".into()))
+    }
+
+    /// Counts an attempt against the key before it's made, so concurrent attempts can't all
+    /// get past the limit before any of them has failed. The attempt counts as failed unless
+    /// it's released, see [Self::release].
+    /// Returns an AppError while the key has failed too often, dropping failures that have expired.
+    pub fn reserve(&self, key: K) -> AppResult<Instant> {
+        let mut failures = self.lock_safe()?;
+        failures.retain(|_, times| {
+            times.retain(|failed_at| failed_at.elapsed() < self.window);
+            !times.is_empty()
+        });
+
+        let times = failures.entry(key).or_default();
+        if times.len() >= self.max_failures {
+            return Err(AppError(
+                ErrorCode::TooManyRequests,
+                "Too many failed attempts, try again later.".into(),
+            ));
+        }
+
+        let attempt = Instant::now();
+        times.push(attempt);
+        Ok(attempt)
+    }
+
+    /// Stops counting an attempt reserved with [Self::reserve], once it turned out not to fail.
+    pub fn release(&self, key: &K, attempt: Instant) -> AppResult<()> {
+        let mut failures = self.lock_safe()?;
+        if let Some(times) = failures.get_mut(key) {
+            if let Some(index) = times.iter().position(|time| *time == attempt) {
+                times.remove(index);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the request's session or API token to the user it belongs to.
+/// Rejects the request with `401 Unauthorized` if the token is missing, unknown or expired.
+#[async_trait]
+impl FromRequestParts<AppContext> for VerifiedUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        ctx: &AppContext,
+    ) -> Result<Self, Self::Rejection> {
+        let login_required = || AppError(ErrorCode::Unauthorized, "Login required".into());
+
+        let token = request_token(&parts.headers).ok_or_else(login_required)?;
+
+        if token.starts_with(API_TOKEN_PREFIX) {
+            let api_token = ctx
+                .db
+                .get_api_token_by_hash(hash_token(&token))
+                .await?
+                .ok_or_else(login_required)?;
+
+            ctx.db.touch_api_token(api_token.id).await?;
+
+            let user = ctx.db.get_user_by_id(api_token.user_id).await?;
+
+            return Ok(VerifiedUser::new(user).with_scope(api_token.scope));
         }
+
+        let session = ctx
+            .db
+            .get_session_by_token(hash_token(&token))
+            .await?
+            .ok_or_else(login_required)?;
+
+        let client = ClientInfo::from_parts(parts, &ctx.auth.trusted_proxies);
+        ctx.db
+            .touch_session(
+                session.id,
+                client.ip,
+                ctx.auth.sessions.expires_at(session.created_at),
+            )
+            .await?;
+
+        let user = ctx.db.get_user_by_id(session.user_id).await?;
+
+        Ok(VerifiedUser::new(user).with_session(session.uuid))
     }
 }
 
 #[derive(Clone)]
 pub struct VerifiedUser {
     inner: Arc<Mutex<model::User>>,
+    session: Option<Uuid>,
+    scope: model::Scope,
 }
 
 impl VerifiedUser {
+    /// Creates a user with full access to their account, as granted by a passkey login.
     pub fn new(user: model::User) -> Self {
         Self {
             inner: Arc::new(Mutex::new(user)),
+            session: None,
+            scope: model::Scope::Admin,
+        }
+    }
+
+    /// Limits what the user is allowed to do, e.g. to the scope of the API token they used.
+    pub fn with_scope(mut self, scope: model::Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Ensures the request was made with at least the required scope.
+    /// If not, returns AppError(ErrorCode::Forbidden, ..)
+    pub fn require_scope(&self, scope: model::Scope) -> AppResult<()> {
+        if self.scope < scope {
+            return Err(AppError(
+                ErrorCode::Forbidden,
+                format!("Requires the {} scope.", scope.as_str()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Ensures the request was made with a session rather than an API token, whatever its scope,
+    /// so a leaked token can't be used to enroll passkeys, replace recovery codes or mint more tokens.
+    /// If not, returns AppError(ErrorCode::Forbidden, ..)
+    pub fn require_session(&self) -> AppResult<()> {
+        if self.session.is_none() {
+            return Err(AppError(
+                ErrorCode::Forbidden,
+                "Requires signing in with a passkey.".into(),
+            ));
         }
+
+        Ok(())
+    }
+
+    /// Records which session the user was verified through.
+    pub fn with_session(mut self, session_uuid: Uuid) -> Self {
+        self.session = Some(session_uuid);
+        self
+    }
+
+    /// Returns the UUID of the session the request was made with, if it was made with one.
+    pub fn session(&self) -> Option<Uuid> {
+        self.session
     }
 
     fn user_lock_safe(&self) -> Result<MutexGuard<model::User>, AppError> {
@@ -63,7 +638,7 @@ impl VerifiedUser {
             Ok(user_lock) => user_lock,
             Err(_) => {
                 return Err(AppError(
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Internal,
                     "VerifiedUser was poisoned.".into(),
                 ))
             }
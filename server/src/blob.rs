@@ -0,0 +1,196 @@
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{error::ErrorCode, AppError};
+
+/// Bytes read from or written to a [BlobStore].
+pub type BlobStream<'a> = BoxStream<'a, io::Result<Bytes>>;
+
+#[derive(Debug)]
+pub enum BlobError {
+    NotFound,
+    InvalidKey,
+    Io(io::Error),
+}
+
+impl From<io::Error> for BlobError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<BlobError> for io::Error {
+    fn from(err: BlobError) -> Self {
+        match err {
+            BlobError::NotFound => io::ErrorKind::NotFound.into(),
+            BlobError::InvalidKey => {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid blob key")
+            }
+            BlobError::Io(err) => err,
+        }
+    }
+}
+
+impl From<BlobError> for AppError {
+    fn from(err: BlobError) -> Self {
+        match err {
+            BlobError::NotFound => AppError(ErrorCode::NotFound, "Media not found.".into()),
+            err => {
+                tracing::error!(error = ?err, "blob store error");
+                AppError(ErrorCode::Internal, "Internal server error".into())
+            }
+        }
+    }
+}
+
+/// Where uploaded media is kept, addressed by key.
+/// Modeled on S3-style object storage so a remote implementation can be swapped in.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Writes the stream to `key`, replacing anything already there. Returns the number of bytes written.
+    async fn put(&self, key: &str, body: BlobStream<'_>) -> Result<u64, BlobError>;
+    async fn get(&self, key: &str) -> Result<BlobStream<'static>, BlobError>;
+    /// Reads the bytes of the blob within `range`, which has to end within the blob.
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<BlobStream<'static>, BlobError>;
+    async fn delete(&self, key: &str) -> Result<(), BlobError>;
+}
+
+/// Reads a whole blob into memory.
+pub async fn read_all(blobs: &dyn BlobStore, key: &str) -> Result<Vec<u8>, BlobError> {
+    let data = blobs
+        .get(key)
+        .await?
+        .try_fold(Vec::new(), |mut data, chunk| async move {
+            data.extend_from_slice(&chunk);
+            Ok(data)
+        })
+        .await?;
+
+    Ok(data)
+}
+
+/// A copy of a blob in the system's temporary directory, for tools that need a seekable file.
+/// The file is removed when this is dropped.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub async fn download(blobs: &dyn BlobStore, key: &str) -> Result<Self, BlobError> {
+        let temp = TempFile(std::env::temp_dir().join(format!("memory-{}", Uuid::new_v4())));
+
+        let mut file = fs::File::create(temp.path()).await?;
+        let mut body = blobs.get(key).await?;
+        while let Some(chunk) = body.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(temp)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Keeps blobs as files in a directory on the local filesystem.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Creates `root` if it doesn't exist yet.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+        // Keys are generated by the server, but never let one escape the root.
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            || key.starts_with('.')
+        {
+            return Err(BlobError::InvalidKey);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    /// Writes to a temporary file first so a failed upload never leaves a partial blob at `key`.
+    async fn put(&self, key: &str, mut body: BlobStream<'_>) -> Result<u64, BlobError> {
+        let path = self.path(key)?;
+        let partial = path.with_extension("partial");
+
+        let mut file = fs::File::create(&partial).await?;
+        let mut written = 0;
+        let result: io::Result<()> = async {
+            while let Some(chunk) = body.try_next().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await
+        }
+        .await;
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&partial).await;
+            return Err(err.into());
+        }
+
+        fs::rename(&partial, &path).await?;
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str) -> Result<BlobStream<'static>, BlobError> {
+        let file = fs::File::open(self.path(key)?).await?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<BlobStream<'static>, BlobError> {
+        let mut file = fs::File::open(self.path(key)?).await?;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(ReaderStream::new(file.take(range.end - range.start)).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use axum::{
+    http::{HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{access, action, api, validation::FieldError};
+
+/// Header carrying the id of the request, on both the request and the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: Uuid;
+}
+
+/// Id of the request currently being handled, if any.
+pub fn request_id() -> Option<Uuid> {
+    REQUEST_ID.try_with(|id| *id).ok()
+}
+
+/// Assigns every request an id, available through [request_id] while it is handled
+/// and returned to the client in the `x-request-id` header.
+pub async fn assign_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let id = Uuid::new_v4();
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Kinds of errors clients can expect, serialized as the `code` of an error response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    PreconditionFailed,
+    PreconditionRequired,
+    ValidationFailed,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    TooManyRequests,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            ErrorCode::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AppError(pub ErrorCode, pub String);
+
+pub type AppResult<T> = Result<T, AppError>;
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: ErrorCode,
+    message: String,
+    request_id: Option<Uuid>,
+    /// Only set when the request failed validation.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+/// Builds the JSON body every error is returned with.
+pub fn error_response(code: ErrorCode, message: String, errors: Vec<FieldError>) -> Response {
+    let request_id = request_id();
+    tracing::debug!(?request_id, ?code, %message, "request failed");
+
+    let body = ErrorResponse {
+        code,
+        message,
+        request_id,
+        errors,
+    };
+    (code.status(), Json(body)).into_response()
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        Self(ErrorCode::Internal, error.to_string())
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        error_response(self.0, self.1, Vec::new())
+    }
+}
+
+impl From<access::AccessError> for AppError {
+    fn from(err: access::AccessError) -> Self {
+        match err {
+            access::AccessError::Sql(sqlx::Error::RowNotFound) => {
+                AppError(ErrorCode::NotFound, "Not found.".into())
+            }
+            access::AccessError::Sql(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                AppError(ErrorCode::Conflict, "Already exists.".into())
+            }
+            access::AccessError::Api(api::ApiError::Decode) => {
+                AppError(ErrorCode::BadRequest, "Invalid input.".into())
+            }
+            access::AccessError::StaleVersion => AppError(
+                ErrorCode::PreconditionFailed,
+                "Story was modified by another request.".into(),
+            ),
+            access::AccessError::App(err) => err,
+            err => {
+                tracing::error!(request_id = ?request_id(), error = ?err, "access error");
+                AppError(ErrorCode::Internal, "Internal server error".into())
+            }
+        }
+    }
+}
+
+impl From<action::ActionError> for AppError {
+    fn from(err: action::ActionError) -> Self {
+        match err {
+            action::ActionError::AccessError(err) => err.into(),
+        }
+    }
+}
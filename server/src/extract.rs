@@ -0,0 +1,73 @@
+use std::ops::Deref;
+
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use axum_macros::{FromRequest, FromRequestParts};
+use serde::Serialize;
+
+use crate::{
+    error::{request_id, ErrorCode},
+    AppError,
+};
+
+/// [axum::extract::Path], rejecting requests with the same error body as handlers do.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct Path<T>(pub T);
+
+/// [axum::extract::Query], rejecting requests with the same error body as handlers do.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct Query<T>(pub T);
+
+/// [axum::Json], rejecting requests with the same error body as handlers do.
+/// Also used for responses, which are serialized just like [axum::Json].
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct Json<T>(pub T);
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Rejections are the client's fault unless axum says otherwise,
+/// e.g. when a route and its handler disagree on the path's parameters.
+fn rejection_error(status: StatusCode, message: String) -> AppError {
+    if status.is_server_error() {
+        tracing::error!(request_id = ?request_id(), %message, "extractor failed");
+        return AppError(ErrorCode::Internal, "Internal server error".into());
+    }
+
+    AppError(ErrorCode::BadRequest, message)
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        rejection_error(rejection.status(), rejection.body_text())
+    }
+}
@@ -0,0 +1,306 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderName},
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, CredentialID, Passkey, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse,
+};
+
+use crate::{
+    access::{credential::AccessCredential, user::AccessUser},
+    action, api,
+    auth::{
+        self, ClientInfo, PendingAuthentication, PendingCredential, PendingRegistration,
+        VerifiedUser,
+    },
+    error::ErrorCode,
+    extract::Json,
+    handlers::user::CreateUserRequest,
+    model,
+    validation::ValidJson,
+    AppContext, AppError,
+};
+
+/// Name given to a passkey enrolled through account recovery.
+const RECOVERED_CREDENTIAL_NAME: &'static str = "Recovered passkey";
+
+/// Returned once a ceremony completes. The token is also set as the session cookie.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionResponse {
+    token: String,
+    user: api::User,
+    /// Only present right after registration, the codes can't be retrieved again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+}
+
+type SessionCookie = [(HeaderName, String); 1];
+
+fn with_session_cookie(
+    config: &auth::SessionConfig,
+    token: String,
+    user: api::User,
+    recovery_codes: Option<Vec<String>>,
+) -> (SessionCookie, Json<SessionResponse>) {
+    (
+        [(header::SET_COOKIE, auth::session_cookie(&token, config))],
+        Json(SessionResponse {
+            token,
+            user,
+            recovery_codes,
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartRegistrationResponse {
+    ceremony_uuid: Uuid,
+    options: CreationChallengeResponse,
+}
+
+/// Begins a passkey registration ceremony for a new user.
+/// The returned options should be passed to the platform authenticator.
+pub async fn handle_register_start(
+    ctx: State<AppContext>,
+    user: Option<VerifiedUser>,
+    request: ValidJson<CreateUserRequest>,
+) -> Result<Json<StartRegistrationResponse>, AppError> {
+    if user.is_some() {
+        return Err(AppError(ErrorCode::BadRequest, "Already signed in.".into()));
+    }
+
+    let user_uuid = Uuid::new_v4();
+    let (options, state) = ctx.auth.webauthn.start_passkey_registration(
+        user_uuid,
+        &request.name,
+        &request.name,
+        None,
+    )?;
+
+    let ceremony_uuid = ctx.auth.registrations.insert(PendingRegistration {
+        user_uuid,
+        name: request.name.clone(),
+        state,
+    })?;
+
+    Ok(Json(StartRegistrationResponse {
+        ceremony_uuid,
+        options,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinishRegistrationRequest {
+    ceremony_uuid: Uuid,
+    credential: RegisterPublicKeyCredential,
+}
+
+/// Verifies the authenticator's response, then creates the user, stores their passkey
+/// and signs them in. The response includes the user's initial recovery codes.
+pub async fn handle_register_finish(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    request: Json<FinishRegistrationRequest>,
+) -> Result<(SessionCookie, Json<SessionResponse>), AppError> {
+    let pending = ctx.auth.registrations.take(request.ceremony_uuid)?;
+
+    let passkey = ctx
+        .auth
+        .webauthn
+        .finish_passkey_registration(&request.credential, &pending.state)?;
+
+    let user =
+        action::auth::register_user(&ctx.db, pending.user_uuid, pending.name, passkey).await?;
+    let recovery_codes = action::auth::generate_recovery_codes(&ctx.db, user.id).await?;
+    let token = action::auth::create_session(&ctx.db, &ctx.auth.sessions, user.id, client).await?;
+
+    Ok(with_session_cookie(
+        &ctx.auth.sessions,
+        token,
+        user.into(),
+        Some(recovery_codes),
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StartLoginRequest {
+    user_uuid: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartLoginResponse {
+    ceremony_uuid: Uuid,
+    options: RequestChallengeResponse,
+}
+
+/// Begins a passkey authentication ceremony against the user's registered passkeys.
+pub async fn handle_login_start(
+    ctx: State<AppContext>,
+    request: Json<StartLoginRequest>,
+) -> Result<Json<StartLoginResponse>, AppError> {
+    let user = ctx.db.get_user_by_uuid(request.user_uuid).await?;
+    let passkeys: Vec<Passkey> = ctx
+        .db
+        .get_user_credentials(user.id)
+        .await?
+        .into_iter()
+        .map(|c| c.passkey)
+        .collect();
+
+    if passkeys.is_empty() {
+        return Err(AppError(
+            ErrorCode::BadRequest,
+            "No passkeys registered.".into(),
+        ));
+    }
+
+    let (options, state) = ctx.auth.webauthn.start_passkey_authentication(&passkeys)?;
+
+    let ceremony_uuid = ctx.auth.authentications.insert(PendingAuthentication {
+        user_id: user.id,
+        state,
+    })?;
+
+    Ok(Json(StartLoginResponse {
+        ceremony_uuid,
+        options,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinishLoginRequest {
+    ceremony_uuid: Uuid,
+    credential: PublicKeyCredential,
+}
+
+/// Verifies the authenticator's assertion and issues a new session token.
+pub async fn handle_login_finish(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    request: Json<FinishLoginRequest>,
+) -> Result<(SessionCookie, Json<SessionResponse>), AppError> {
+    let pending = ctx.auth.authentications.take(request.ceremony_uuid)?;
+
+    let result = ctx
+        .auth
+        .webauthn
+        .finish_passkey_authentication(&request.credential, &pending.state)?;
+
+    action::auth::record_authentication(&ctx.db, pending.user_id, &result).await?;
+
+    let user = ctx.db.get_user_by_id(pending.user_id).await?;
+    let token = action::auth::create_session(&ctx.db, &ctx.auth.sessions, user.id, client).await?;
+
+    Ok(with_session_cookie(
+        &ctx.auth.sessions,
+        token,
+        user.into(),
+        None,
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StartRecoveryRequest {
+    user_uuid: Uuid,
+    code: String,
+}
+
+/// Redeems a recovery code and begins a registration ceremony for a replacement passkey.
+/// The code is spent even if the ceremony is never finished.
+/// Failed attempts are limited per user and per address, to keep codes from being guessed.
+pub async fn handle_recover_start(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    request: Json<StartRecoveryRequest>,
+) -> Result<Json<StartRegistrationResponse>, AppError> {
+    let by_ip = &ctx.auth.recovery_failures_by_ip;
+    let by_user = &ctx.auth.recovery_failures_by_user;
+    let ip = client.ip.unwrap_or_default();
+    // Attempts are counted as failed up front and only released once they didn't fail.
+    let ip_attempt = by_ip.reserve(ip.clone())?;
+    let user = ctx.db.get_user_by_uuid(request.user_uuid).await?;
+    let user_attempt = by_user.reserve(user.id)?;
+
+    let redeemed = action::auth::redeem_recovery_code(&ctx.db, user.id, request.code.clone()).await;
+    let guessed_wrong = matches!(&redeemed, Err(err) if err.0 == ErrorCode::Unauthorized);
+    if !guessed_wrong {
+        by_ip.release(&ip, ip_attempt)?;
+        by_user.release(&user.id, user_attempt)?;
+    }
+    redeemed?;
+
+    let exclude_credentials: Vec<CredentialID> = ctx
+        .db
+        .get_user_credentials(user.id)
+        .await?
+        .into_iter()
+        .map(|c| c.passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) = ctx.auth.webauthn.start_passkey_registration(
+        user.uuid,
+        &user.name,
+        &user.name,
+        Some(exclude_credentials),
+    )?;
+
+    let ceremony_uuid = ctx.auth.recoveries.insert(PendingCredential {
+        user_id: user.id,
+        name: RECOVERED_CREDENTIAL_NAME.into(),
+        state,
+    })?;
+
+    Ok(Json(StartRegistrationResponse {
+        ceremony_uuid,
+        options,
+    }))
+}
+
+/// Verifies the replacement passkey, stores it and signs the user in.
+pub async fn handle_recover_finish(
+    ctx: State<AppContext>,
+    client: ClientInfo,
+    request: Json<FinishRegistrationRequest>,
+) -> Result<(SessionCookie, Json<SessionResponse>), AppError> {
+    let pending = ctx.auth.recoveries.take(request.ceremony_uuid)?;
+
+    let passkey = ctx
+        .auth
+        .webauthn
+        .finish_passkey_registration(&request.credential, &pending.state)?;
+
+    ctx.db
+        .create_credential(pending.user_id, pending.name, &passkey)
+        .await?;
+
+    let user = ctx.db.get_user_by_id(pending.user_id).await?;
+    let token = action::auth::create_session(&ctx.db, &ctx.auth.sessions, user.id, client).await?;
+
+    Ok(with_session_cookie(
+        &ctx.auth.sessions,
+        token,
+        user.into(),
+        None,
+    ))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+/// Replaces the verified user's recovery codes, invalidating any they had before.
+pub async fn handle_regenerate_recovery_codes(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<Json<RecoveryCodesResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+    user.require_session()?;
+
+    let recovery_codes = action::auth::generate_recovery_codes(&ctx.db, user.id()?).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
@@ -0,0 +1,192 @@
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use webauthn_rs::prelude::{CreationChallengeResponse, CredentialID, RegisterPublicKeyCredential};
+
+use crate::{
+    access::{credential::AccessCredential, user::AccessUser},
+    api,
+    auth::{PendingCredential, VerifiedUser},
+    error::ErrorCode,
+    extract::{Json, Path},
+    model,
+    validation::{self, ValidJson, Validate, ValidationErrors},
+    AppContext, AppError,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetCredentialsResponse {
+    credentials: Vec<api::Credential>,
+}
+
+/// Lists the passkeys registered to the verified user.
+pub async fn handle_get_credentials(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<Json<GetCredentialsResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    let credentials = ctx
+        .db
+        .get_user_credentials(user.id()?)
+        .await?
+        .into_iter()
+        .map(|c| c.into())
+        .collect();
+
+    Ok(Json(GetCredentialsResponse { credentials }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CredentialRequest {
+    name: String,
+}
+
+impl Validate for CredentialRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check_length(
+            "name",
+            &self.name,
+            true,
+            validation::MAX_CREDENTIAL_NAME_LENGTH,
+        );
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartCredentialResponse {
+    ceremony_uuid: Uuid,
+    options: CreationChallengeResponse,
+}
+
+/// Begins a registration ceremony that adds another passkey to the verified user.
+/// Passkeys the user already has are excluded so the same authenticator isn't enrolled twice.
+pub async fn handle_credential_start(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    request: ValidJson<CredentialRequest>,
+) -> Result<Json<StartCredentialResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+    user.require_session()?;
+
+    let user = ctx.db.get_user(&user).await?;
+    let exclude_credentials: Vec<CredentialID> = ctx
+        .db
+        .get_user_credentials(user.id)
+        .await?
+        .into_iter()
+        .map(|c| c.passkey.cred_id().clone())
+        .collect();
+
+    let (options, state) = ctx.auth.webauthn.start_passkey_registration(
+        user.uuid,
+        &user.name,
+        &user.name,
+        Some(exclude_credentials),
+    )?;
+
+    let ceremony_uuid = ctx
+        .auth
+        .credential_registrations
+        .insert(PendingCredential {
+            user_id: user.id,
+            name: request.name.clone(),
+            state,
+        })?;
+
+    Ok(Json(StartCredentialResponse {
+        ceremony_uuid,
+        options,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinishCredentialRequest {
+    ceremony_uuid: Uuid,
+    credential: RegisterPublicKeyCredential,
+}
+
+/// Verifies the authenticator's response and stores the additional passkey.
+pub async fn handle_credential_finish(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    request: Json<FinishCredentialRequest>,
+) -> Result<Json<api::Credential>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+    user.require_session()?;
+
+    let pending = ctx
+        .auth
+        .credential_registrations
+        .take(request.ceremony_uuid)?;
+    if pending.user_id != user.id()? {
+        return Err(AppError(
+            ErrorCode::BadRequest,
+            "Unknown or expired ceremony.".into(),
+        ));
+    }
+
+    let passkey = ctx
+        .auth
+        .webauthn
+        .finish_passkey_registration(&request.credential, &pending.state)?;
+
+    let credential = ctx
+        .db
+        .create_credential(pending.user_id, pending.name, &passkey)
+        .await?;
+
+    Ok(Json(credential.into()))
+}
+
+/// Gives one of the verified user's passkeys a new friendly name.
+pub async fn handle_rename_credential(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(credential_uuid): Path<Uuid>,
+    request: ValidJson<CredentialRequest>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    if !ctx
+        .db
+        .rename_credential(user.id()?, credential_uuid, request.name.clone())
+        .await?
+    {
+        return Err(AppError(ErrorCode::NotFound, "Passkey not found.".into()));
+    }
+
+    Ok(Json(()))
+}
+
+/// Removes one of the verified user's passkeys. The last remaining passkey can't be removed.
+pub async fn handle_delete_credential(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(credential_uuid): Path<Uuid>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+    user.require_session()?;
+
+    let user_id = user.id()?;
+    if ctx.db.delete_credential(user_id, credential_uuid).await? {
+        return Ok(Json(()));
+    }
+
+    let exists = ctx
+        .db
+        .get_user_credentials(user_id)
+        .await?
+        .iter()
+        .any(|c| c.uuid == credential_uuid);
+    if exists {
+        return Err(AppError(
+            ErrorCode::Conflict,
+            "Can't remove your only passkey.".into(),
+        ));
+    }
+
+    Err(AppError(ErrorCode::NotFound, "Passkey not found.".into()))
+}
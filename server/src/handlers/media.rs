@@ -0,0 +1,218 @@
+use std::io;
+
+use axum::{
+    body::StreamBody,
+    extract::{Multipart, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    action::{self, media::MediaDownload},
+    api,
+    auth::VerifiedUser,
+    error::ErrorCode,
+    extract::{Json, Path, Query},
+    model, AppContext, AppError,
+};
+
+/// Name of the multipart field holding the uploaded file.
+const FILE_FIELD: &str = "file";
+
+/// Name of the optional multipart field opting out of metadata stripping.
+/// It has to come before the file, which is streamed as soon as it's reached.
+const KEEP_METADATA_FIELD: &str = "keep_metadata";
+
+/// Name of the optional multipart field holding the file's hex encoded SHA-256.
+/// When it matches one of the user's uploads the existing media is returned without reading the file.
+const SHA256_FIELD: &str = "sha256";
+
+/// Uploads a file as multipart form data, streaming it into the blob store.
+/// The returned uuid can be referenced from image, audio or video content as `media`.
+/// EXIF metadata is removed from the served file unless `keep_metadata` is `true`.
+/// Uploading a file the user already has returns the existing media.
+pub async fn handle_upload_media(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    mut multipart: Multipart,
+) -> Result<Json<api::Media>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    let invalid = |message: String| AppError(ErrorCode::BadRequest, message);
+    let mut keep_metadata = false;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| invalid(err.body_text()))?
+    {
+        if field.name() == Some(KEEP_METADATA_FIELD) {
+            let value = field.text().await.map_err(|err| invalid(err.body_text()))?;
+            keep_metadata = match value.as_str() {
+                "true" => true,
+                "false" => false,
+                _ => {
+                    return Err(invalid(format!(
+                        "`{}` must be `true` or `false`.",
+                        KEEP_METADATA_FIELD
+                    )))
+                }
+            };
+            continue;
+        }
+
+        if field.name() == Some(SHA256_FIELD) {
+            let sha256 = field.text().await.map_err(|err| invalid(err.body_text()))?;
+            if let Some(media) =
+                action::media::find_media_by_sha256(&ctx.db, &user, sha256.trim()).await?
+            {
+                return Ok(Json(media));
+            }
+            continue;
+        }
+
+        if field.name() != Some(FILE_FIELD) {
+            continue;
+        }
+
+        let content_type = field.content_type().unwrap_or_default().to_string();
+        if !action::media::ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(invalid(format!(
+                "Unsupported content type, expected one of {}.",
+                action::media::ALLOWED_CONTENT_TYPES.join(", ")
+            )));
+        }
+
+        let body = Box::pin(field.map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+        let media = action::media::upload_media(
+            &ctx.db,
+            ctx.blobs.as_ref(),
+            &ctx.variants,
+            &ctx.uploads,
+            &user,
+            content_type,
+            keep_metadata,
+            None,
+            body,
+        )
+        .await?;
+
+        return Ok(Json(media));
+    }
+
+    Err(invalid(format!("Missing `{}` field.", FILE_FIELD)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetMediaQuery {
+    /// Variant to download instead of the original.
+    size: Option<model::VariantSize>,
+}
+
+/// Downloads media uploaded by the user.
+/// A `Range: bytes=...` header downloads only part of the file, so players can seek.
+/// Ranges that can't be parsed are ignored and the whole file is sent, as HTTP allows.
+/// With `If-Range` the range is only honored while it matches the file's `ETag`.
+pub async fn handle_get_media(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(media_uuid): Path<Uuid>,
+    Query(query): Query<GetMediaQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let if_range = headers
+        .get(header::IF_RANGE)
+        .and_then(|value| value.to_str().ok());
+
+    let download = action::media::get_media(
+        &ctx.db,
+        ctx.blobs.as_ref(),
+        &user,
+        media_uuid,
+        query.size,
+        range,
+        if_range,
+    )
+    .await?;
+
+    Ok(media_response(download, true))
+}
+
+/// Downloads the frame extracted from the user's video, 404 until one has been extracted.
+pub async fn handle_get_media_poster(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(media_uuid): Path<Uuid>,
+) -> Result<Response, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let download =
+        action::media::get_media_poster(&ctx.db, ctx.blobs.as_ref(), &user, media_uuid).await?;
+
+    // Posters are always sent whole.
+    Ok(media_response(download, false))
+}
+
+/// `accept_ranges` advertises Range support, only for downloads that honor it.
+fn media_response(download: MediaDownload, accept_ranges: bool) -> Response {
+    if download.unsatisfiable {
+        let error = AppError(
+            ErrorCode::RangeNotSatisfiable,
+            format!("Range isn't within the file's {} bytes.", download.size),
+        );
+        let content_range = format!("bytes */{}", download.size);
+        return ([(header::CONTENT_RANGE, content_range)], error).into_response();
+    }
+
+    let (status, length, content_range) = match &download.range {
+        Some(range) => (
+            StatusCode::PARTIAL_CONTENT,
+            range.end - range.start,
+            Some(format!(
+                "bytes {}-{}/{}",
+                range.start,
+                range.end - 1,
+                download.size
+            )),
+        ),
+        None => (StatusCode::OK, download.size, None),
+    };
+
+    // Media never changes once uploaded, but a variant that's still being generated is
+    // stood in for by another file, which has to be replaced once the variant is ready.
+    let cache_control = if download.fallback {
+        "private, no-cache"
+    } else {
+        "private, max-age=31536000, immutable"
+    };
+    let mut headers = vec![
+        (header::CONTENT_TYPE, download.content_type),
+        (header::CONTENT_LENGTH, length.to_string()),
+        (header::CACHE_CONTROL, cache_control.into()),
+        (header::ETAG, download.etag),
+        // The content type was checked on upload, browsers mustn't second-guess it.
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".into()),
+    ];
+    if accept_ranges {
+        headers.push((header::ACCEPT_RANGES, "bytes".into()));
+    }
+    if let Some(content_range) = content_range {
+        headers.push((header::CONTENT_RANGE, content_range));
+    }
+
+    (
+        status,
+        AppendHeaders(headers),
+        StreamBody::new(download.body),
+    )
+        .into_response()
+}
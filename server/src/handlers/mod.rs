@@ -1,9 +1,15 @@
-use axum::{extract::State, Json};
+use axum::extract::State;
 use serde::Serialize;
 
-use crate::{action, api, AppContext, AppError};
+use crate::{action, api, extract::Json, AppContext, AppError};
 
+pub mod auth;
+pub mod credential;
+pub mod media;
+pub mod session;
 pub mod story;
+pub mod token;
+pub mod upload;
 pub mod user;
 
 #[derive(Debug, Clone, Serialize)]
@@ -20,3 +26,547 @@ pub async fn get_prompts(ctx: State<AppContext>) -> Result<Json<GetPromptsRespon
 
     Ok(Json(GetPromptsResponse { prompts }))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::{Body, HttpBody},
+        http::{header, Method, Request, StatusCode},
+        Router,
+    };
+    use bytes::Bytes;
+    use chrono::Utc;
+    use futures_util::{stream, StreamExt};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use sqlx::MySqlPool;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::{
+        access::{
+            api_token::AccessApiToken, media::AccessMedia, session::AccessSession,
+            user::AccessUser, MemoryDb,
+        },
+        action, api, auth,
+        auth::VerifiedUser,
+        blob,
+        error::ErrorCode,
+        model, AppContext,
+    };
+
+    /// Everything the first user owns, which the second user must not be able to see or change.
+    struct Owned {
+        owner: VerifiedUser,
+        story: api::Story,
+        trashed: Uuid,
+        credential: Uuid,
+        session: Uuid,
+        api_token: Uuid,
+        media: Uuid,
+        upload: Uuid,
+    }
+
+    fn context(db: MemoryDb) -> AppContext {
+        let relying_party = auth::RelyingPartyConfig {
+            id: "localhost".into(),
+            name: "Memory".into(),
+            origins: vec!["http://localhost:3000".parse().unwrap()],
+        };
+        let sessions = auth::SessionConfig {
+            idle_timeout: Duration::from_secs(60 * 60),
+            max_lifetime: Duration::from_secs(60 * 60),
+        };
+        let media_dir = std::env::temp_dir().join(format!("memory-test-{}", Uuid::new_v4()));
+        let (variants, _) = tokio::sync::mpsc::unbounded_channel();
+
+        AppContext {
+            db,
+            auth: auth::AuthState::new(relying_party, sessions, Vec::new()).unwrap(),
+            blobs: Arc::new(blob::LocalBlobStore::new(media_dir).unwrap()),
+            variants,
+            uploads: action::upload::UploadConfig {
+                quota: 1024 * 1024,
+                storage_quota: 1024 * 1024,
+                expiry: Duration::from_secs(60 * 60),
+            },
+        }
+    }
+
+    /// Creates a user with a session, returning the user and the session's bearer token.
+    async fn login(db: &MemoryDb, name: &str) -> (VerifiedUser, model::Session, String) {
+        let user = db.create_user(Uuid::new_v4(), name.into()).await.unwrap();
+        let token = auth::generate_token();
+        let session = db
+            .create_session(
+                user.id,
+                auth::hash_token(&token),
+                None,
+                None,
+                Utc::now() + chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+
+        (VerifiedUser::new(user), session, token)
+    }
+
+    fn text(body: &str) -> api::ContentDetails {
+        api::ContentDetails::Text(api::TextContent {
+            title: String::new(),
+            body: body.into(),
+        })
+    }
+
+    async fn create_owned(ctx: &AppContext, pool: &MySqlPool) -> Owned {
+        let db = &ctx.db;
+        let (owner, session, _) = login(db, "owner").await;
+        let owner_id = owner.id().unwrap();
+
+        // Located, so it would show up on the map.
+        let location = api::StoryLocation {
+            latitude: 51.5,
+            longitude: -0.1,
+            place_name: None,
+        };
+        let story = action::create_story(
+            db,
+            &owner,
+            "Mine".into(),
+            Some(location),
+            vec![text("first")],
+        )
+        .await
+        .unwrap();
+        let trashed = action::create_story(db, &owner, "Deleted".into(), None, vec![])
+            .await
+            .unwrap();
+        action::delete_story(db, &owner, trashed.uuid, None)
+            .await
+            .unwrap();
+
+        // Registering a passkey needs an authenticator, the row is all the endpoints look at.
+        let credential = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO credentials (uuid, name, credential_id, passkey, user_id) VALUES (?, 'Passkey', ?, '{}', ?)",
+            credential.to_string(),
+            credential.as_bytes().to_vec(),
+            owner_id
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        let api_token = db
+            .create_api_token(
+                owner_id,
+                "Token".into(),
+                auth::hash_token(&auth::generate_token()),
+                model::Scope::Read,
+            )
+            .await
+            .unwrap();
+
+        let media = db
+            .create_media(
+                owner_id,
+                Uuid::new_v4(),
+                "image/png".into(),
+                4,
+                "missing".into(),
+                "0".repeat(64),
+                false,
+            )
+            .await
+            .unwrap();
+
+        let upload = action::upload::create_upload(
+            db,
+            &ctx.uploads,
+            &owner,
+            "image/png".into(),
+            4,
+            "0".repeat(64),
+            false,
+        )
+        .await
+        .unwrap();
+
+        Owned {
+            owner,
+            story,
+            trashed: trashed.uuid,
+            credential,
+            session: session.uuid,
+            api_token: api_token.uuid,
+            media: media.uuid,
+            upload: upload.uuid,
+        }
+    }
+
+    /// Builds a request as the holder of `token`.
+    /// Every request carries the headers any endpoint requires, so only ownership can fail it.
+    fn request(token: &str, method: Method, uri: &str, body: Option<Value>) -> Request<Body> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .header(header::IF_MATCH, "*")
+            .header("upload-offset", "0")
+            .header(header::CONTENT_TYPE, "application/json");
+        let body = match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        };
+
+        request.body(body).unwrap()
+    }
+
+    /// Sends a request as the holder of `token`, returning the response's status.
+    async fn send(
+        app: &Router,
+        token: &str,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> StatusCode {
+        app.clone()
+            .oneshot(request(token, method, uri, body))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    /// Gets `uri` as the holder of `token`, returning the response's JSON body.
+    async fn get_json(app: &Router, token: &str, uri: &str) -> Value {
+        let response = app
+            .clone()
+            .oneshot(request(token, Method::GET, uri, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "GET {}", uri);
+
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn second_user_gets_not_found_from_every_endpoint(pool: MySqlPool) {
+        let ctx = context(MemoryDb::new(pool.clone()));
+        let owned = create_owned(&ctx, &pool).await;
+        let (intruder, _, token) = login(&ctx.db, "intruder").await;
+        let own_story = action::create_story(&ctx.db, &intruder, "Theirs".into(), None, vec![])
+            .await
+            .unwrap();
+        let app = crate::router(ctx.clone(), 1024 * 1024);
+
+        let story = owned.story.uuid;
+        let content = owned.story.content[0].uuid;
+        let requests = vec![
+            (
+                Method::PUT,
+                format!("/credentials/{}", owned.credential),
+                Some(json!({ "name": "Taken" })),
+            ),
+            (
+                Method::DELETE,
+                format!("/credentials/{}", owned.credential),
+                None,
+            ),
+            (Method::DELETE, format!("/sessions/{}", owned.session), None),
+            (Method::DELETE, format!("/tokens/{}", owned.api_token), None),
+            (Method::GET, format!("/stories/{}", story), None),
+            (
+                Method::PUT,
+                format!("/story/{}", story),
+                Some(json!({ "title": "Taken" })),
+            ),
+            (Method::DELETE, format!("/story/{}", story), None),
+            (Method::GET, format!("/story/{}/revisions", story), None),
+            (Method::GET, format!("/story/{}/revisions/1", story), None),
+            (
+                Method::POST,
+                format!("/story/{}/revisions/1/restore", story),
+                None,
+            ),
+            (
+                Method::POST,
+                format!("/story/{}/restore", owned.trashed),
+                None,
+            ),
+            (Method::DELETE, format!("/trash/{}", owned.trashed), None),
+            (Method::GET, format!("/media/{}", owned.media), None),
+            (Method::GET, format!("/media/{}/poster", owned.media), None),
+            (Method::GET, format!("/uploads/{}", owned.upload), None),
+            (Method::PATCH, format!("/uploads/{}", owned.upload), None),
+            (
+                Method::POST,
+                format!("/uploads/{}/finalize", owned.upload),
+                None,
+            ),
+            (Method::DELETE, format!("/uploads/{}", owned.upload), None),
+            // Referring to another user's media or content from the second user's own stories.
+            (
+                Method::POST,
+                "/story".into(),
+                Some(json!({
+                    "title": "Theirs",
+                    "content": [{ "type": "image", "src": "", "description": "", "media": owned.media }],
+                })),
+            ),
+            (
+                Method::PUT,
+                format!("/story/{}", own_story.uuid),
+                Some(
+                    json!({ "content": [{ "op": "update", "uuid": content, "content": { "type": "text", "title": "", "body": "Taken" } }] }),
+                ),
+            ),
+            (
+                Method::PUT,
+                format!("/story/{}", own_story.uuid),
+                Some(json!({ "content": [{ "op": "remove", "uuid": content }] })),
+            ),
+            (
+                Method::PUT,
+                format!("/story/{}", own_story.uuid),
+                Some(json!({ "content": [{ "op": "move", "uuid": content, "position": 0 }] })),
+            ),
+        ];
+
+        for (method, uri, body) in requests {
+            let status = send(&app, &token, method.clone(), &uri, body).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, uri);
+        }
+
+        // Lists only ever show the second user's own things.
+        let page = action::list_stories(&ctx.db, &intruder, None, 100, true)
+            .await
+            .unwrap();
+        assert_eq!(page.stories.len(), 1);
+        assert!(action::trash::list_trash(&ctx.db, &intruder)
+            .await
+            .unwrap()
+            .is_empty());
+        let lists = [
+            "/stories",
+            "/stories/map?bbox=-180,-90,180,90",
+            "/trash",
+            "/credentials",
+            "/sessions",
+            "/tokens",
+        ];
+        let foreign = [
+            owned.story.uuid,
+            owned.trashed,
+            owned.credential,
+            owned.session,
+            owned.api_token,
+        ];
+        for uri in lists {
+            let body = get_json(&app, &token, uri).await.to_string();
+            for uuid in foreign.iter() {
+                assert!(
+                    !body.contains(&uuid.to_string()),
+                    "GET {} shows {}",
+                    uri,
+                    uuid
+                );
+            }
+        }
+
+        // And nothing the first user owns has changed.
+        let story = action::get_story(&ctx.db, &owned.owner, story)
+            .await
+            .unwrap();
+        assert_eq!(story.title, "Mine");
+        assert_eq!(story.version, owned.story.version);
+        assert_eq!(story.content.len(), 1);
+        assert_eq!(
+            story.content[0].updated_at,
+            owned.story.content[0].updated_at
+        );
+        assert_eq!(
+            action::trash::list_trash(&ctx.db, &owned.owner)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(ctx
+            .db
+            .get_media_by_uuid(&owned.owner, owned.media)
+            .await
+            .is_ok());
+    }
+
+    #[sqlx::test]
+    async fn admin_api_token_cannot_manage_the_account(pool: MySqlPool) {
+        let ctx = context(MemoryDb::new(pool.clone()));
+        let owned = create_owned(&ctx, &pool).await;
+        let token = format!("{}{}", auth::API_TOKEN_PREFIX, auth::generate_token());
+        ctx.db
+            .create_api_token(
+                owned.owner.id().unwrap(),
+                "Admin".into(),
+                auth::hash_token(&token),
+                model::Scope::Admin,
+            )
+            .await
+            .unwrap();
+        let app = crate::router(ctx.clone(), 1024 * 1024);
+
+        let requests = vec![
+            (
+                Method::POST,
+                "/credentials/register/start".to_string(),
+                Some(json!({ "name": "Stolen" })),
+            ),
+            (
+                Method::POST,
+                "/credentials/register/finish".into(),
+                Some(json!({
+                    "ceremony_uuid": Uuid::new_v4(),
+                    "credential": {
+                        "id": "AA",
+                        "rawId": "AA",
+                        "response": { "attestationObject": "AA", "clientDataJSON": "AA" },
+                        "type": "public-key",
+                    },
+                })),
+            ),
+            (
+                Method::DELETE,
+                format!("/credentials/{}", owned.credential),
+                None,
+            ),
+            (Method::POST, "/recovery-codes".into(), None),
+            (
+                Method::POST,
+                "/tokens".into(),
+                Some(json!({ "name": "More", "scope": "admin" })),
+            ),
+        ];
+
+        for (method, uri, body) in requests {
+            let status = send(&app, &token, method.clone(), &uri, body).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, uri);
+        }
+
+        // The token still works wherever its scope is enough.
+        let status = send(&app, &token, Method::GET, "/tokens", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            ctx.db
+                .get_user_api_tokens(owned.owner.id().unwrap())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[sqlx::test]
+    async fn stale_or_weak_if_match_fails_with_the_current_etag(pool: MySqlPool) {
+        let ctx = context(MemoryDb::new(pool));
+        let (user, _, token) = login(&ctx.db, "user").await;
+        let story = action::create_story(&ctx.db, &user, "Mine".into(), None, vec![])
+            .await
+            .unwrap();
+        let app = crate::router(ctx.clone(), 1024 * 1024);
+        let current = format!("\"{}\"", story.version);
+
+        for if_match in [
+            format!("W/{}", current),
+            format!("\"{}\"", story.version + 1),
+        ] {
+            let mut request = request(
+                &token,
+                Method::PUT,
+                &format!("/story/{}", story.uuid),
+                Some(json!({ "title": "Changed" })),
+            );
+            request
+                .headers_mut()
+                .insert(header::IF_MATCH, if_match.parse().unwrap());
+            let response = app.clone().oneshot(request).await.unwrap();
+
+            assert_eq!(
+                response.status(),
+                StatusCode::PRECONDITION_FAILED,
+                "{}",
+                if_match
+            );
+            assert_eq!(response.headers()[header::ETAG], current.as_str());
+        }
+
+        let story = action::get_story(&ctx.db, &user, story.uuid).await.unwrap();
+        assert_eq!(story.title, "Mine");
+    }
+
+    #[sqlx::test]
+    async fn upload_over_the_storage_quota_is_discarded_on_finalize(pool: MySqlPool) {
+        let ctx = context(MemoryDb::new(pool));
+        let (user, _, _) = login(&ctx.db, "user").await;
+        let png = Bytes::from_static(b"\x89PNG\r\n\x1a\n");
+
+        let upload = action::upload::create_upload(
+            &ctx.db,
+            &ctx.uploads,
+            &user,
+            "image/png".into(),
+            png.len() as u64,
+            hex::encode(Sha256::digest(&png)),
+            false,
+        )
+        .await
+        .unwrap();
+        action::upload::append_upload(
+            &ctx.db,
+            ctx.blobs.as_ref(),
+            &ctx.uploads,
+            &user,
+            upload.uuid,
+            0,
+            stream::once(async move { Ok(png) }).boxed(),
+        )
+        .await
+        .unwrap();
+
+        // Media uploaded directly while the upload was unfinished takes up the rest of the quota.
+        ctx.db
+            .create_media(
+                user.id().unwrap(),
+                Uuid::new_v4(),
+                "image/png".into(),
+                ctx.uploads.storage_quota,
+                "missing".into(),
+                "0".repeat(64),
+                false,
+            )
+            .await
+            .unwrap();
+
+        let err = action::upload::finalize_upload(
+            &ctx.db,
+            ctx.blobs.as_ref(),
+            &ctx.variants,
+            &ctx.uploads,
+            &user,
+            upload.uuid,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, ErrorCode::PayloadTooLarge);
+
+        let err = action::upload::get_upload(&ctx.db, &user, upload.uuid)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, ErrorCode::NotFound);
+    }
+}
@@ -0,0 +1,68 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderName},
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    access::session::AccessSession,
+    api, auth,
+    auth::VerifiedUser,
+    error::ErrorCode,
+    extract::{Json, Path},
+    model, AppContext, AppError,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetSessionsResponse {
+    sessions: Vec<api::Session>,
+}
+
+/// Lists the verified user's active sessions across all of their devices.
+pub async fn handle_get_sessions(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<Json<GetSessionsResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    let sessions = ctx
+        .db
+        .get_user_sessions(user.id()?)
+        .await?
+        .into_iter()
+        .map(|s| s.into_api(user.session()))
+        .collect();
+
+    Ok(Json(GetSessionsResponse { sessions }))
+}
+
+/// Revokes one of the verified user's sessions, e.g. one belonging to a lost device.
+pub async fn handle_delete_session(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(session_uuid): Path<Uuid>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    if !ctx.db.delete_session(user.id()?, session_uuid).await? {
+        return Err(AppError(ErrorCode::NotFound, "Session not found.".into()));
+    }
+
+    Ok(Json(()))
+}
+
+/// Invalidates the session the request was made with and clears the session cookie.
+pub async fn handle_logout(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<([(HeaderName, String); 1], Json<()>), AppError> {
+    if let Some(session_uuid) = user.session() {
+        ctx.db.delete_session(user.id()?, session_uuid).await?;
+    }
+
+    Ok((
+        [(header::SET_COOKIE, auth::clear_session_cookie())],
+        Json(()),
+    ))
+}
@@ -1,28 +1,69 @@
 use axum::{
-    extract::{Path, State},
-    Json,
+    extract::State,
+    http::{header, HeaderMap, HeaderName},
+    response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
-use crate::{access::story::AccessStory, action, api, AppContext, AppError};
+use crate::{
+    action, api,
+    auth::VerifiedUser,
+    error::ErrorCode,
+    extract::{Json, Path, Query},
+    model,
+    validation::{self, ValidJson, Validate, ValidationErrors},
+    AppContext, AppError,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateStoryRequest {
     title: String,
+    #[serde(default)]
+    location: Option<api::StoryLocation>,
     content: Vec<api::ContentDetails>,
 }
 
+impl Validate for CreateStoryRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check_length(
+            "title",
+            &self.title,
+            true,
+            validation::MAX_STORY_TITLE_LENGTH,
+        );
+        if let Some(location) = &self.location {
+            errors.check_story_location("location", location);
+        }
+        if self.content.len() > validation::MAX_CONTENT_BLOCKS {
+            errors.add(
+                "content",
+                format!(
+                    "Must have at most {} blocks.",
+                    validation::MAX_CONTENT_BLOCKS
+                ),
+            );
+        }
+        for (i, content) in self.content.iter().enumerate() {
+            errors.check_content(&format!("content[{}]", i), content);
+        }
+        errors.into_result()
+    }
+}
+
 pub async fn handle_create_story(
     ctx: State<AppContext>,
-    request: Json<CreateStoryRequest>,
+    user: VerifiedUser,
+    request: ValidJson<CreateStoryRequest>,
 ) -> Result<Json<api::Story>, AppError> {
-    let user = ctx.auth.authenticated()?;
+    user.require_scope(model::Scope::Write)?;
 
     let story = action::create_story(
         &ctx.db,
-        user,
+        &user,
         request.title.clone(),
+        request.location.clone(),
         request.content.clone(),
     )
     .await?;
@@ -30,68 +71,352 @@ pub async fn handle_create_story(
     Ok(Json(story))
 }
 
+type StoryETag = [(HeaderName, String); 1];
+
+/// The story's version as a strong entity tag, e.g. `"3"`.
+fn story_etag(story: &api::Story) -> StoryETag {
+    [(header::ETAG, format!("\"{}\"", story.version))]
+}
+
+/// Reads the story version a client expects from `If-Match`.
+/// Edits must send one, `*` matches any version. Tags are compared strongly,
+/// so a weak tag never matches.
+fn expected_version(headers: &HeaderMap) -> Result<Option<u32>, AppError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .ok_or(AppError(
+            ErrorCode::PreconditionRequired,
+            "If-Match header is required.".into(),
+        ))?
+        .to_str()
+        .map_err(|_| AppError(ErrorCode::BadRequest, "Invalid If-Match header.".into()))?
+        .trim();
+
+    if value == "*" {
+        return Ok(None);
+    }
+    if value.starts_with("W/") {
+        return Err(AppError(
+            ErrorCode::PreconditionFailed,
+            "If-Match needs the story's strong ETag, weak ones never match.".into(),
+        ));
+    }
+
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or(AppError(
+            ErrorCode::BadRequest,
+            "Invalid If-Match header.".into(),
+        ))
+}
+
+/// Adds the story's current `ETag` to a failed precondition, so the client knows which version
+/// it's up against. Other errors are returned as they are.
+async fn with_current_etag(
+    ctx: &AppContext,
+    user: &VerifiedUser,
+    story_uuid: Uuid,
+    err: AppError,
+) -> Result<Response, AppError> {
+    if err.0 != ErrorCode::PreconditionFailed {
+        return Err(err);
+    }
+
+    let story = action::get_story(&ctx.db, user, story_uuid).await?;
+    Ok((story_etag(&story), err).into_response())
+}
+
 pub async fn handle_get_story(
     ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(story_uuid): Path<Uuid>,
+) -> Result<(StoryETag, Json<api::Story>), AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let story = action::get_story(&ctx.db, &user, story_uuid).await?;
+    Ok((story_etag(&story), Json(story)))
+}
+
+/// Default number of stories returned by `GET /stories`.
+const DEFAULT_PAGE_LIMIT: u32 = 20;
+/// Upper bound on the `limit` a client can request.
+const MAX_PAGE_LIMIT: u32 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListStoriesQuery {
+    cursor: Option<String>,
+    limit: Option<u32>,
+    include_content: Option<bool>,
+}
+
+pub async fn handle_list_stories(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Query(query): Query<ListStoriesQuery>,
+) -> Result<Json<api::StoryPage>, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let cursor = match query.cursor {
+        Some(cursor) => Some(
+            api::StoryCursor::decode(&cursor)
+                .map_err(|_| AppError(ErrorCode::BadRequest, "Invalid cursor.".into()))?,
+        ),
+        None => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+
+    let page = action::list_stories(
+        &ctx.db,
+        &user,
+        cursor,
+        limit,
+        query.include_content.unwrap_or(true),
+    )
+    .await?;
+
+    Ok(Json(page))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StoryMapQuery {
+    /// Area to show, as `west,south,east,north` in degrees.
+    /// West is greater than east when the area crosses the antimeridian.
+    bbox: String,
+    /// Zoom level of the map, as used by web map tiles. Estimated from `bbox` when not given.
+    zoom: Option<u8>,
+}
+
+/// Returns the user's stories within an area of the map, grouped into clusters when zoomed out.
+pub async fn handle_get_story_map(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Query(query): Query<StoryMapQuery>,
+) -> Result<Json<api::StoryMap>, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let bbox = query.bbox.parse().map_err(|_| {
+        AppError(
+            ErrorCode::BadRequest,
+            "Invalid bbox, expected `west,south,east,north` in degrees with south below north."
+                .into(),
+        )
+    })?;
+
+    let map = action::map::get_story_map(&ctx.db, &user, bbox, query.zoom).await?;
+    Ok(Json(map))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetTrashResponse {
+    stories: Vec<api::Story>,
+}
+
+/// Lists the stories the verified user has deleted but not yet purged.
+pub async fn handle_get_trash(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<Json<GetTrashResponse>, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let stories = action::trash::list_trash(&ctx.db, &user).await?;
+    Ok(Json(GetTrashResponse { stories }))
+}
+
+pub async fn handle_restore_story(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
     Path(story_uuid): Path<Uuid>,
 ) -> Result<Json<api::Story>, AppError> {
-    let user = ctx.auth.authenticated()?;
+    user.require_scope(model::Scope::Write)?;
 
-    let story = action::get_story(&ctx.db, user, story_uuid).await?;
+    let story = action::trash::restore_story(&ctx.db, &user, story_uuid).await?;
     Ok(Json(story))
 }
 
+pub async fn handle_purge_story(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(story_uuid): Path<Uuid>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    action::trash::purge_story(&ctx.db, ctx.blobs.as_ref(), &user, story_uuid).await?;
+    Ok(Json(()))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UpdateStoryRequest {
     title: Option<String>,
-    content: Vec<UpdateContentRequest>,
+    /// Left alone when missing, removed when `null`.
+    #[serde(default, deserialize_with = "deserialize_present")]
+    location: Option<Option<api::StoryLocation>>,
+    #[serde(default)]
+    content: Vec<ContentOperationRequest>,
 }
 
+/// Tells a field sent as `null` apart from a missing one, which `#[serde(default)]` leaves as `None`.
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// A single change to a story's content. Operations are applied in order.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct UpdateContentRequest {
-    uuid: Uuid,
-    content: api::ContentDetails,
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ContentOperationRequest {
+    /// Overwrites an existing content block.
+    Update {
+        uuid: Uuid,
+        content: api::ContentDetails,
+    },
+    /// Inserts a new content block at `position`, or appends it when no position is given.
+    Add {
+        content: api::ContentDetails,
+        position: Option<u32>,
+    },
+    /// Removes a content block.
+    Remove { uuid: Uuid },
+    /// Moves an existing content block to `position`.
+    Move { uuid: Uuid, position: u32 },
 }
 
-impl Into<action::ContentUpdate> for UpdateContentRequest {
-    fn into(self) -> action::ContentUpdate {
-        action::ContentUpdate {
-            uuid: self.uuid,
-            content: self.content,
+impl Validate for UpdateStoryRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(title) = &self.title {
+            errors.check_length("title", title, true, validation::MAX_STORY_TITLE_LENGTH);
+        }
+        if let Some(Some(location)) = &self.location {
+            errors.check_story_location("location", location);
+        }
+        for (i, op) in self.content.iter().enumerate() {
+            match op {
+                ContentOperationRequest::Update { content, .. }
+                | ContentOperationRequest::Add { content, .. } => {
+                    errors.check_content(&format!("content[{}].content", i), content)
+                }
+                ContentOperationRequest::Remove { .. } | ContentOperationRequest::Move { .. } => {}
+            }
+        }
+        errors.into_result()
+    }
+}
+
+impl Into<action::ContentOperation> for ContentOperationRequest {
+    fn into(self) -> action::ContentOperation {
+        match self {
+            Self::Update { uuid, content } => action::ContentOperation::Update { uuid, content },
+            Self::Add { content, position } => action::ContentOperation::Add { content, position },
+            Self::Remove { uuid } => action::ContentOperation::Remove { uuid },
+            Self::Move { uuid, position } => action::ContentOperation::Move { uuid, position },
         }
     }
 }
 
 pub async fn handle_update_story(
     ctx: State<AppContext>,
+    user: VerifiedUser,
+    headers: HeaderMap,
     story_uuid: Path<Uuid>,
-    request: Json<UpdateStoryRequest>,
-) -> Result<Json<api::Story>, AppError> {
-    let user = ctx.auth.authenticated()?;
-
-    let story = ctx.db.get_story_by_uuid(user, story_uuid.0).await?;
-
-    action::update_story(&ctx.db, user, story.clone(), request.title.clone(), None).await?;
+    request: ValidJson<UpdateStoryRequest>,
+) -> Result<Response, AppError> {
+    user.require_scope(model::Scope::Write)?;
+    let expected_version = match expected_version(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return with_current_etag(&ctx, &user, story_uuid.0, err).await,
+    };
 
-    let content_updates = request
+    let content_operations = request
         .content
         .clone()
         .into_iter()
-        .map(|u| u.into())
+        .map(|op| op.into())
         .collect();
 
-    action::update_content(&ctx.db, &story, content_updates).await?;
-
-    let story = action::get_story(&ctx.db, user, story_uuid.0).await?;
+    let result = action::update_story(
+        &ctx.db,
+        &user,
+        story_uuid.0,
+        expected_version,
+        request.title.clone(),
+        request.location.clone(),
+        content_operations,
+    )
+    .await;
 
-    Ok(Json(story))
+    match result {
+        Ok(story) => Ok((story_etag(&story), Json(story)).into_response()),
+        Err(err) => with_current_etag(&ctx, &user, story_uuid.0, err).await,
+    }
 }
 
 pub async fn handle_delete_story(
     ctx: State<AppContext>,
+    user: VerifiedUser,
+    headers: HeaderMap,
     story_uuid: Path<Uuid>,
-) -> Result<Json<()>, AppError> {
-    let user = ctx.auth.authenticated()?;
+) -> Result<Response, AppError> {
+    user.require_scope(model::Scope::Write)?;
+    let expected_version = match expected_version(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return with_current_etag(&ctx, &user, story_uuid.0, err).await,
+    };
+
+    match action::delete_story(&ctx.db, &user, story_uuid.0, expected_version).await {
+        Ok(response) => Ok(Json(response).into_response()),
+        Err(err) => with_current_etag(&ctx, &user, story_uuid.0, err).await,
+    }
+}
 
-    let response = action::delete_story(&ctx.db, user, story_uuid.0).await?;
-    Ok(Json(response))
+pub async fn handle_get_revisions(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(story_uuid): Path<Uuid>,
+) -> Result<Json<Vec<api::RevisionSummary>>, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let revisions = action::revision::list_revisions(&ctx.db, &user, story_uuid).await?;
+    Ok(Json(revisions))
+}
+
+pub async fn handle_get_revision(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path((story_uuid, version)): Path<(Uuid, u32)>,
+) -> Result<Json<api::Revision>, AppError> {
+    user.require_scope(model::Scope::Read)?;
+
+    let revision = action::revision::get_revision(&ctx.db, &user, story_uuid, version).await?;
+    Ok(Json(revision))
+}
+
+pub async fn handle_restore_revision(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    headers: HeaderMap,
+    Path((story_uuid, version)): Path<(Uuid, u32)>,
+) -> Result<Response, AppError> {
+    user.require_scope(model::Scope::Write)?;
+    let expected_version = match expected_version(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return with_current_etag(&ctx, &user, story_uuid, err).await,
+    };
+
+    let result =
+        action::revision::restore_revision(&ctx.db, &user, story_uuid, version, expected_version)
+            .await;
+
+    match result {
+        Ok(story) => Ok((story_etag(&story), Json(story)).into_response()),
+        Err(err) => with_current_etag(&ctx, &user, story_uuid, err).await,
+    }
 }
@@ -0,0 +1,104 @@
+use axum::extract::State;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    access::api_token::AccessApiToken,
+    api, auth,
+    auth::VerifiedUser,
+    error::ErrorCode,
+    extract::{Json, Path},
+    model,
+    validation::{self, ValidJson, Validate, ValidationErrors},
+    AppContext, AppError,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    scope: model::Scope,
+}
+
+impl Validate for CreateApiTokenRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check_length(
+            "name",
+            &self.name,
+            true,
+            validation::MAX_API_TOKEN_NAME_LENGTH,
+        );
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateApiTokenResponse {
+    /// Only returned once, the token can't be retrieved again.
+    token: String,
+    api_token: api::ApiToken,
+}
+
+/// Creates a personal API token for scripts that can't perform a passkey ceremony.
+pub async fn handle_create_api_token(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    request: ValidJson<CreateApiTokenRequest>,
+) -> Result<Json<CreateApiTokenResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+    user.require_session()?;
+
+    let token = format!("{}{}", auth::API_TOKEN_PREFIX, auth::generate_token());
+    let api_token = ctx
+        .db
+        .create_api_token(
+            user.id()?,
+            request.name.clone(),
+            auth::hash_token(&token),
+            request.scope,
+        )
+        .await?;
+
+    Ok(Json(CreateApiTokenResponse {
+        token,
+        api_token: api_token.into(),
+    }))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GetApiTokensResponse {
+    api_tokens: Vec<api::ApiToken>,
+}
+
+/// Lists the verified user's API tokens. The tokens themselves are never returned.
+pub async fn handle_get_api_tokens(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+) -> Result<Json<GetApiTokensResponse>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    let api_tokens = ctx
+        .db
+        .get_user_api_tokens(user.id()?)
+        .await?
+        .into_iter()
+        .map(|t| t.into())
+        .collect();
+
+    Ok(Json(GetApiTokensResponse { api_tokens }))
+}
+
+/// Revokes one of the verified user's API tokens.
+pub async fn handle_delete_api_token(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(token_uuid): Path<Uuid>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Admin)?;
+
+    if !ctx.db.delete_api_token(user.id()?, token_uuid).await? {
+        return Err(AppError(ErrorCode::NotFound, "API token not found.".into()));
+    }
+
+    Ok(Json(()))
+}
@@ -0,0 +1,172 @@
+use std::io;
+
+use axum::{
+    extract::{BodyStream, State},
+    http::HeaderMap,
+};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    action, api,
+    auth::VerifiedUser,
+    error::ErrorCode,
+    extract::{Json, Path},
+    model,
+    validation::{ValidJson, Validate, ValidationErrors},
+    AppContext, AppError,
+};
+
+/// Header carrying where an appended chunk starts, which must match the upload's `offset`.
+const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateUploadRequest {
+    content_type: String,
+    /// Total size of the file, in bytes.
+    size: u64,
+    /// Hex encoded SHA-256 of the whole file, checked when the upload is finalized.
+    sha256: String,
+    #[serde(default)]
+    keep_metadata: bool,
+}
+
+impl Validate for CreateUploadRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if !action::media::ALLOWED_CONTENT_TYPES.contains(&self.content_type.as_str()) {
+            errors.add(
+                "content_type",
+                format!(
+                    "Must be one of {}.",
+                    action::media::ALLOWED_CONTENT_TYPES.join(", ")
+                ),
+            );
+        }
+        let max_size = action::media::max_size(&self.content_type);
+        if self.size == 0 {
+            errors.add("size", "Must be greater than 0.");
+        } else if self.size > max_size {
+            errors.add("size", format!("Must be at most {} bytes.", max_size));
+        }
+        if !self.keep_metadata && !action::media::can_strip_metadata(&self.content_type) {
+            errors.add(
+                "keep_metadata",
+                "Must be true for images that aren't JPEG, PNG, GIF or WebP.",
+            );
+        }
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            errors.add("sha256", "Must be a hex encoded SHA-256.");
+        }
+        errors.into_result()
+    }
+}
+
+/// Starts a resumable upload. Chunks are then sent with `PATCH` until the whole file is received,
+/// and `POST /uploads/:upload_uuid/finalize` turns it into media.
+pub async fn handle_create_upload(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    request: ValidJson<CreateUploadRequest>,
+) -> Result<Json<api::Upload>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    let upload = action::upload::create_upload(
+        &ctx.db,
+        &ctx.uploads,
+        &user,
+        request.content_type.clone(),
+        request.size,
+        request.sha256.clone(),
+        request.keep_metadata,
+    )
+    .await?;
+
+    Ok(Json(upload))
+}
+
+/// Returns the upload's progress, used to find where to resume from.
+pub async fn handle_get_upload(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(upload_uuid): Path<Uuid>,
+) -> Result<Json<api::Upload>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    let upload = action::upload::get_upload(&ctx.db, &user, upload_uuid).await?;
+    Ok(Json(upload))
+}
+
+/// Appends the request body to the upload, starting at the `Upload-Offset` header.
+pub async fn handle_append_upload(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(upload_uuid): Path<Uuid>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Json<api::Upload>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .ok_or(AppError(
+            ErrorCode::BadRequest,
+            "Upload-Offset header is required.".into(),
+        ))?
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or(AppError(
+            ErrorCode::BadRequest,
+            "Invalid Upload-Offset header.".into(),
+        ))?;
+
+    let body = Box::pin(body.map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+    let upload = action::upload::append_upload(
+        &ctx.db,
+        ctx.blobs.as_ref(),
+        &ctx.uploads,
+        &user,
+        upload_uuid,
+        offset,
+        body,
+    )
+    .await?;
+
+    Ok(Json(upload))
+}
+
+/// Checks the received file against its SHA-256 and stores it as media.
+/// The returned uuid can be referenced from content as `media`.
+pub async fn handle_finalize_upload(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(upload_uuid): Path<Uuid>,
+) -> Result<Json<api::Media>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    let media = action::upload::finalize_upload(
+        &ctx.db,
+        ctx.blobs.as_ref(),
+        &ctx.variants,
+        &ctx.uploads,
+        &user,
+        upload_uuid,
+    )
+    .await?;
+
+    Ok(Json(media))
+}
+
+/// Abandons an unfinished upload.
+pub async fn handle_cancel_upload(
+    ctx: State<AppContext>,
+    user: VerifiedUser,
+    Path(upload_uuid): Path<Uuid>,
+) -> Result<Json<()>, AppError> {
+    user.require_scope(model::Scope::Write)?;
+
+    action::upload::cancel_upload(&ctx.db, ctx.blobs.as_ref(), &user, upload_uuid).await?;
+    Ok(Json(()))
+}
@@ -1,22 +1,35 @@
 use axum::{
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get, post, put},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use clap::Parser;
 use sqlx::mysql::MySqlPoolOptions;
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tower_http::trace::TraceLayer;
+use webauthn_rs::prelude::Url;
 
 mod access;
 mod action;
 mod api;
 mod auth;
+mod blob;
+mod error;
+mod extract;
 mod handlers;
+mod metadata;
 mod model;
+mod probe;
+mod validation;
 
 use access::MemoryDb;
+pub use error::{AppError, AppResult};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -35,46 +48,86 @@ struct Args {
     mysql_port: String,
     #[arg(env = "MYSQL_HOST", default_value = "localhost:3306")]
     mysql_host: String,
-}
 
-#[derive(Debug)]
-pub struct AppError(StatusCode, String);
+    /// Domain passkeys are bound to
+    #[arg(long, env = "RP_ID", default_value = "localhost")]
+    rp_id: String,
+    /// Name authenticators show for this server
+    #[arg(long, env = "RP_NAME", default_value = "Memory")]
+    rp_name: String,
+    /// Comma separated origins allowed to perform passkey ceremonies,
+    /// e.g. the web client and the iOS associated domain
+    #[arg(
+        long,
+        env = "RP_ORIGINS",
+        value_delimiter = ',',
+        default_value = "http://localhost:3000"
+    )]
+    rp_origins: Vec<Url>,
+    /// Comma separated addresses of reverse proxies trusted to report the client's address
+    /// in `X-Forwarded-For`. Without one, the address of the connection is recorded.
+    #[arg(long, env = "TRUSTED_PROXY", value_delimiter = ',')]
+    trusted_proxy: Vec<IpAddr>,
 
-pub type AppResult<T> = Result<T, AppError>;
+    /// Days a deleted story stays in the trash before it is purged, at most 100 years
+    #[arg(
+        long,
+        env = "TRASH_RETENTION_DAYS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..=36_500)
+    )]
+    trash_retention_days: u64,
 
-impl From<anyhow::Error> for AppError {
-    fn from(error: anyhow::Error) -> Self {
-        Self(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
-    }
-}
+    /// Seconds a session may go unused before it expires, at most the maximum lifetime
+    #[arg(
+        long,
+        env = "SESSION_IDLE_TIMEOUT",
+        default_value_t = 60 * 60 * 24 * 14,
+        value_parser = clap::value_parser!(u64).range(1..=315_360_000)
+    )]
+    session_idle_timeout: u64,
+    /// Seconds after login a session expires, regardless of activity, at most 10 years
+    #[arg(
+        long,
+        env = "SESSION_MAX_LIFETIME",
+        default_value_t = 60 * 60 * 24 * 90,
+        value_parser = clap::value_parser!(u64).range(1..=315_360_000)
+    )]
+    session_max_lifetime: u64,
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        (self.0, format!("{}", self.1)).into_response()
-    }
+    /// Directory uploaded media is stored in
+    #[arg(long, env = "MEDIA_DIR", default_value = "media")]
+    media_dir: PathBuf,
+    /// Largest upload accepted by `POST /media`, in bytes
+    #[arg(long, env = "MAX_UPLOAD_SIZE", default_value_t = 50 * 1024 * 1024)]
+    max_upload_size: usize,
+    /// ffmpeg binary used to extract poster frames from uploaded video, none are extracted without it
+    #[arg(long, env = "FFMPEG")]
+    ffmpeg: Option<PathBuf>,
+    /// Bytes of unfinished resumable uploads each user may have at once
+    #[arg(long, env = "UPLOAD_QUOTA", default_value_t = 2 * 1024 * 1024 * 1024)]
+    upload_quota: u64,
+    /// Bytes of media each user may store, including unfinished uploads
+    #[arg(long, env = "STORAGE_QUOTA", default_value_t = 20 * 1024 * 1024 * 1024)]
+    storage_quota: u64,
+    /// Hours a resumable upload may go without receiving a chunk before it is discarded,
+    /// at most a year
+    #[arg(
+        long,
+        env = "UPLOAD_EXPIRY_HOURS",
+        default_value_t = 24,
+        value_parser = clap::value_parser!(u64).range(1..=8_760)
+    )]
+    upload_expiry_hours: u64,
 }
 
-impl From<access::AccessError> for AppError {
-    fn from(err: access::AccessError) -> Self {
-        println!("{:?}", err);
-        AppError(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal server error".into(),
-        )
-    }
-}
-
-impl From<action::ActionError> for AppError {
-    fn from(err: action::ActionError) -> Self {
-        match err {
-            action::ActionError::AccessError(err) => err.into(),
-        }
-    }
-}
 #[derive(Clone)]
 pub struct AppContext {
     pub db: MemoryDb,
     pub auth: auth::AuthState,
+    pub blobs: Arc<dyn blob::BlobStore>,
+    pub variants: action::media::VariantQueue,
+    pub uploads: action::upload::UploadConfig,
 }
 
 #[tokio::main]
@@ -85,6 +138,15 @@ async fn main() {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    if args.session_idle_timeout > args.session_max_lifetime {
+        tracing::error!(
+            "Session idle timeout of {}s is longer than the maximum lifetime of {}s",
+            args.session_idle_timeout,
+            args.session_max_lifetime
+        );
+        std::process::exit(1);
+    }
+
     let mysql_url = format!(
         "mysql://{}:{}@{}/{}",
         args.mysql_user, args.mysql_password, args.mysql_host, args.mysql_database
@@ -98,17 +160,153 @@ async fn main() {
 
     sqlx::migrate!("./migrations").run(&pool).await.unwrap();
 
-    let auth_state = auth::AuthState::new();
+    let relying_party = auth::RelyingPartyConfig {
+        id: args.rp_id,
+        name: args.rp_name,
+        origins: args.rp_origins,
+    };
+    let sessions = auth::SessionConfig {
+        idle_timeout: Duration::from_secs(args.session_idle_timeout),
+        max_lifetime: Duration::from_secs(args.session_max_lifetime),
+    };
+    let auth_state = match auth::AuthState::new(relying_party, sessions, args.trusted_proxy) {
+        Ok(auth_state) => auth_state,
+        Err(err) => {
+            tracing::error!("Invalid WebAuthn configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let blobs = match blob::LocalBlobStore::new(&args.media_dir) {
+        Ok(blobs) => blobs,
+        Err(err) => {
+            tracing::error!(
+                "Unable to use media directory {:?}: {}",
+                args.media_dir,
+                err
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let db = MemoryDb::new(pool);
+    let blobs: Arc<dyn blob::BlobStore> = Arc::new(blobs);
+    tokio::spawn(action::trash::purge_periodically(
+        db.clone(),
+        blobs.clone(),
+        Duration::from_secs(args.trash_retention_days * 60 * 60 * 24),
+    ));
+
+    let (variants, variant_queue) = tokio::sync::mpsc::unbounded_channel();
+    match action::media::enqueue_unprocessed_media(&db, &variants).await {
+        Ok(queued) => tracing::info!(queued, "queued unprocessed media"),
+        Err(err) => tracing::error!(error = ?err, "failed to queue unprocessed media"),
+    }
+    tokio::spawn(action::media::generate_variants_forever(
+        db.clone(),
+        blobs.clone(),
+        args.ffmpeg.clone(),
+        variant_queue,
+    ));
+    tokio::spawn(action::media::collect_media_periodically(
+        db.clone(),
+        blobs.clone(),
+    ));
+    tokio::spawn(action::upload::expire_uploads_periodically(
+        db.clone(),
+        blobs.clone(),
+    ));
+
+    let uploads = action::upload::UploadConfig {
+        quota: args.upload_quota,
+        storage_quota: args.storage_quota,
+        expiry: Duration::from_secs(args.upload_expiry_hours * 60 * 60),
+    };
 
     let context = AppContext {
-        db: MemoryDb::new(pool),
+        db,
         auth: auth_state,
+        blobs,
+        variants,
+        uploads,
     };
 
-    let app = Router::new()
+    let app = router(context, args.max_upload_size);
+
+    println!("Listening on {} 🚀", &args.listener);
+    axum::Server::bind(&args.listener)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+/// Routes every endpoint to its handler, `max_upload_size` limits the body of `POST /media`.
+fn router(context: AppContext, max_upload_size: usize) -> Router {
+    Router::new()
         .route("/prompts", get(handlers::get_prompts))
-        .route("/user", post(handlers::user::create_user))
+        .route(
+            "/auth/register/start",
+            post(handlers::auth::handle_register_start),
+        )
+        .route(
+            "/auth/register/finish",
+            post(handlers::auth::handle_register_finish),
+        )
+        .route(
+            "/auth/login/start",
+            post(handlers::auth::handle_login_start),
+        )
+        .route(
+            "/auth/login/finish",
+            post(handlers::auth::handle_login_finish),
+        )
+        .route(
+            "/auth/recover/start",
+            post(handlers::auth::handle_recover_start),
+        )
+        .route(
+            "/auth/recover/finish",
+            post(handlers::auth::handle_recover_finish),
+        )
+        .route(
+            "/recovery-codes",
+            post(handlers::auth::handle_regenerate_recovery_codes),
+        )
+        .route(
+            "/credentials",
+            get(handlers::credential::handle_get_credentials),
+        )
+        .route(
+            "/credentials/register/start",
+            post(handlers::credential::handle_credential_start),
+        )
+        .route(
+            "/credentials/register/finish",
+            post(handlers::credential::handle_credential_finish),
+        )
+        .route(
+            "/credentials/:credential_uuid",
+            put(handlers::credential::handle_rename_credential),
+        )
+        .route(
+            "/credentials/:credential_uuid",
+            delete(handlers::credential::handle_delete_credential),
+        )
+        .route("/logout", post(handlers::session::handle_logout))
+        .route("/sessions", get(handlers::session::handle_get_sessions))
+        .route(
+            "/sessions/:session_uuid",
+            delete(handlers::session::handle_delete_session),
+        )
+        .route("/tokens", get(handlers::token::handle_get_api_tokens))
+        .route("/tokens", post(handlers::token::handle_create_api_token))
+        .route(
+            "/tokens/:token_uuid",
+            delete(handlers::token::handle_delete_api_token),
+        )
         .route("/user", get(handlers::user::get_verified_user))
+        .route("/stories", get(handlers::story::handle_list_stories))
+        .route("/stories/map", get(handlers::story::handle_get_story_map))
         .route(
             "/stories/:story_uuid",
             get(handlers::story::handle_get_story),
@@ -122,12 +320,55 @@ async fn main() {
             "/story/:story_uuid",
             put(handlers::story::handle_update_story),
         )
+        .route(
+            "/story/:story_uuid/restore",
+            post(handlers::story::handle_restore_story),
+        )
+        .route(
+            "/story/:story_uuid/revisions",
+            get(handlers::story::handle_get_revisions),
+        )
+        .route(
+            "/story/:story_uuid/revisions/:version",
+            get(handlers::story::handle_get_revision),
+        )
+        .route(
+            "/story/:story_uuid/revisions/:version/restore",
+            post(handlers::story::handle_restore_revision),
+        )
+        .route("/trash", get(handlers::story::handle_get_trash))
+        .route(
+            "/media",
+            post(handlers::media::handle_upload_media)
+                .layer(DefaultBodyLimit::max(max_upload_size)),
+        )
+        .route("/media/:media_uuid", get(handlers::media::handle_get_media))
+        .route(
+            "/media/:media_uuid/poster",
+            get(handlers::media::handle_get_media_poster),
+        )
+        .route("/uploads", post(handlers::upload::handle_create_upload))
+        .route(
+            "/uploads/:upload_uuid",
+            get(handlers::upload::handle_get_upload),
+        )
+        .route(
+            "/uploads/:upload_uuid",
+            patch(handlers::upload::handle_append_upload),
+        )
+        .route(
+            "/uploads/:upload_uuid",
+            delete(handlers::upload::handle_cancel_upload),
+        )
+        .route(
+            "/uploads/:upload_uuid/finalize",
+            post(handlers::upload::handle_finalize_upload),
+        )
+        .route(
+            "/trash/:story_uuid",
+            delete(handlers::story::handle_purge_story),
+        )
+        .layer(middleware::from_fn(error::assign_request_id))
         .layer(TraceLayer::new_for_http())
-        .with_state(context);
-
-    println!("Listening on {} 🚀", &args.listener);
-    axum::Server::bind(&args.listener)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .with_state(context)
 }
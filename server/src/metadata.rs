@@ -0,0 +1,280 @@
+use std::io::{BufRead, Cursor, Seek};
+
+use chrono::NaiveDate;
+use exif::{In, Reader, Tag, Value};
+use image::{DynamicImage, ImageError, ImageOutputFormat};
+
+use crate::model;
+
+const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+/// Bits of a WebP `VP8X` chunk's flags announcing EXIF and XMP chunks.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+/// Matches `media.camera_make` and `media.camera_model`.
+const MAX_CAMERA_LENGTH: usize = 100;
+
+/// Reads capture time, orientation, location and camera from the file's EXIF, if it has any.
+pub fn read_metadata<R: BufRead + Seek>(reader: &mut R) -> model::MediaMetadata {
+    let exif = match Reader::new().read_from_container(reader) {
+        Ok(exif) => exif,
+        Err(_) => return model::MediaMetadata::default(),
+    };
+
+    let ascii = |tag: Tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values
+            .first()
+            .map(|v| String::from_utf8_lossy(v).trim().to_string())
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    };
+
+    // Degrees, minutes and seconds, negated for the southern and western hemispheres.
+    let coordinate = |tag: Tag, ref_tag: Tag, negative: &str| {
+        let degrees = match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+            Some(Value::Rational(parts)) if parts.len() == 3 => {
+                parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        match ascii(ref_tag).as_deref() {
+            Some(r) if r == negative => Some(-degrees),
+            _ => Some(degrees),
+        }
+    };
+
+    let captured_at =
+        ascii(Tag::DateTimeOriginal)
+            .or_else(|| ascii(Tag::DateTime))
+            .and_then(|value| exif::DateTime::from_ascii(value.as_bytes()).ok())
+            .and_then(|dt| {
+                NaiveDate::from_ymd_opt(dt.year.into(), dt.month.into(), dt.day.into())?
+                    .and_hms_opt(dt.hour.into(), dt.minute.into(), dt.second.into())
+            });
+
+    model::MediaMetadata {
+        captured_at,
+        latitude: coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        longitude: coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .map(|o| o as u16),
+        camera_make: ascii(Tag::Make).map(|v| v.chars().take(MAX_CAMERA_LENGTH).collect()),
+        camera_model: ascii(Tag::Model).map(|v| v.chars().take(MAX_CAMERA_LENGTH).collect()),
+    }
+}
+
+/// Rotates and flips the image as described by an EXIF orientation.
+pub fn apply_orientation(image: DynamicImage, orientation: Option<u16>) -> DynamicImage {
+    match orientation {
+        Some(2) => image.fliph(),
+        Some(3) => image.rotate180(),
+        Some(4) => image.flipv(),
+        Some(5) => image.rotate90().fliph(),
+        Some(6) => image.rotate90(),
+        Some(7) => image.rotate270().fliph(),
+        Some(8) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Returns a copy of the file without its metadata,
+/// or `None` if the content type isn't supported or the file couldn't be parsed.
+/// Files are stripped without re-encoding, unless a JPEG or PNG needs to be rotated
+/// since the orientation is lost along with the metadata.
+/// WebP can't be re-encoded, so one that needs rotating isn't stripped at all.
+pub fn strip_metadata(
+    content_type: &str,
+    data: &[u8],
+    orientation: Option<u16>,
+) -> Result<Option<Vec<u8>>, ImageError> {
+    let rotated = orientation.map_or(false, |o| o != 1);
+    let format = match content_type {
+        "image/jpeg" => ImageOutputFormat::Jpeg(95),
+        "image/png" => ImageOutputFormat::Png,
+        "image/webp" if rotated => return Ok(None),
+        "image/webp" => return Ok(strip_webp(data)),
+        // GIF has no orientation to lose.
+        "image/gif" => return Ok(strip_gif(data)),
+        _ => return Ok(None),
+    };
+
+    if rotated {
+        let image = apply_orientation(image::load_from_memory(data)?, orientation);
+        let mut stripped = Cursor::new(Vec::new());
+        image.write_to(&mut stripped, format)?;
+        return Ok(Some(stripped.into_inner()));
+    }
+
+    match content_type {
+        "image/jpeg" => Ok(strip_jpeg(data)),
+        _ => Ok(strip_png(data)),
+    }
+}
+
+/// Copies every segment up to the image data except APP1 (EXIF, XMP) and APP13 (IPTC).
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut stripped = vec![0xFF, 0xD8];
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        // Start of scan, everything after is image data.
+        if marker == 0xDA {
+            stripped.extend_from_slice(&data[i..]);
+            return Some(stripped);
+        }
+
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let end = i + 2 + length;
+        if end > data.len() {
+            return None;
+        }
+        if marker != 0xE1 && marker != 0xED {
+            stripped.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+    }
+
+    None
+}
+
+/// Copies every chunk except EXIF and text chunks.
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return None;
+    }
+
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    let mut i = PNG_SIGNATURE.len();
+    while i + 12 <= data.len() {
+        let length = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        let kind = &data[i + 4..i + 8];
+        // Length, type, data and CRC.
+        let end = i + 12 + length;
+        if end > data.len() {
+            return None;
+        }
+        if !matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            stripped.extend_from_slice(&data[i..end]);
+        }
+        if kind == b"IEND" {
+            return Some(stripped);
+        }
+        i = end;
+    }
+
+    None
+}
+
+/// Copies every RIFF chunk except EXIF and XMP, clearing the flags that announce them.
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut stripped = data[0..12].to_vec();
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let kind = &data[i..i + 4];
+        let length =
+            u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        // Type, length and data, padded to an even length.
+        let end = i + 8 + length + length % 2;
+        if end > data.len() {
+            return None;
+        }
+        match kind {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" if length >= 1 => {
+                let flags = stripped.len() + 8;
+                stripped.extend_from_slice(&data[i..end]);
+                stripped[flags] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            }
+            _ => stripped.extend_from_slice(&data[i..end]),
+        }
+        i = end;
+    }
+    if i != data.len() {
+        return None;
+    }
+
+    let riff_length = u32::try_from(stripped.len() - 8).ok()?;
+    stripped[4..8].copy_from_slice(&riff_length.to_le_bytes());
+    Some(stripped)
+}
+
+/// Copies every block except comments and application extensions other than looping,
+/// which is where XMP is kept.
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 13 || !(data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")) {
+        return None;
+    }
+
+    // Header, logical screen descriptor and global color table.
+    let mut i = 13 + color_table_length(data[10]);
+    if i > data.len() {
+        return None;
+    }
+    let mut stripped = data[..i].to_vec();
+
+    while i < data.len() {
+        let end = match data[i] {
+            // Trailer.
+            0x3B => {
+                stripped.push(0x3B);
+                return Some(stripped);
+            }
+            // Image descriptor, local color table, LZW code size and image data.
+            0x2C => {
+                let packed = *data.get(i + 9)?;
+                skip_sub_blocks(data, i + 10 + color_table_length(packed) + 1)?
+            }
+            0x21 => {
+                let end = skip_sub_blocks(data, i + 2)?;
+                let label = *data.get(i + 1)?;
+                let looping = label == 0xFF
+                    && data.get(i + 2) == Some(&11)
+                    && matches!(
+                        data.get(i + 3..i + 14),
+                        Some(b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                    );
+                if label == 0xFE || (label == 0xFF && !looping) {
+                    i = end;
+                    continue;
+                }
+                end
+            }
+            _ => return None,
+        };
+        stripped.extend_from_slice(&data[i..end]);
+        i = end;
+    }
+
+    None
+}
+
+/// Size of the color table described by a GIF's packed fields, if it has one.
+fn color_table_length(packed: u8) -> usize {
+    match packed & 0x80 {
+        0 => 0,
+        _ => 3 << ((packed & 0x07) + 1),
+    }
+}
+
+/// Returns the index after the sub-blocks starting at `i` and their terminator.
+fn skip_sub_blocks(data: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let length = *data.get(i)? as usize;
+        i += 1 + length;
+        if length == 0 {
+            return Some(i);
+        }
+    }
+}
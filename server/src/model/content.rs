@@ -2,7 +2,7 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::api;
+use crate::api::{self, ContentDetailsFormat, TaggedContentDetails, VersionedContentDetails};
 
 #[derive(Debug, Clone, Serialize, sqlx::FromRow)]
 pub struct Content {
@@ -11,6 +11,9 @@ pub struct Content {
     pub uuid: Uuid,
     pub kind: String,
     pub details: ContentDetails,
+    pub position: u32,
+    /// Uploaded media the details refer to, counted in the media's `ref_count`.
+    pub media_id: Option<u32>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -27,12 +30,69 @@ impl Into<api::Content> for Content {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-#[serde(rename_all = "snake_case")]
+/// Stored in `content.details` in the tagged format, see [VersionedContentDetails].
+/// Rows from before the format was tagged are still read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(
+    from = "ContentDetailsFormat<ImageContent, TextContent, AudioContent, VideoContent, LocationContent>",
+    into = "VersionedContentDetails<ImageContent, TextContent, AudioContent, VideoContent, LocationContent>"
+)]
 pub enum ContentDetails {
     Image(ImageContent),
     Text(TextContent),
+    Audio(AudioContent),
+    Video(VideoContent),
+    Location(LocationContent),
+}
+
+impl
+    From<
+        ContentDetailsFormat<
+            ImageContent,
+            TextContent,
+            AudioContent,
+            VideoContent,
+            LocationContent,
+        >,
+    > for ContentDetails
+{
+    fn from(
+        format: ContentDetailsFormat<
+            ImageContent,
+            TextContent,
+            AudioContent,
+            VideoContent,
+            LocationContent,
+        >,
+    ) -> Self {
+        match format.into() {
+            TaggedContentDetails::Image(image) => ContentDetails::Image(image),
+            TaggedContentDetails::Text(text) => ContentDetails::Text(text),
+            TaggedContentDetails::Audio(audio) => ContentDetails::Audio(audio),
+            TaggedContentDetails::Video(video) => ContentDetails::Video(video),
+            TaggedContentDetails::Location(location) => ContentDetails::Location(location),
+        }
+    }
+}
+
+impl From<ContentDetails>
+    for VersionedContentDetails<
+        ImageContent,
+        TextContent,
+        AudioContent,
+        VideoContent,
+        LocationContent,
+    >
+{
+    fn from(details: ContentDetails) -> Self {
+        match details {
+            ContentDetails::Image(image) => TaggedContentDetails::Image(image).into(),
+            ContentDetails::Text(text) => TaggedContentDetails::Text(text).into(),
+            ContentDetails::Audio(audio) => TaggedContentDetails::Audio(audio).into(),
+            ContentDetails::Video(video) => TaggedContentDetails::Video(video).into(),
+            ContentDetails::Location(location) => TaggedContentDetails::Location(location).into(),
+        }
+    }
 }
 
 impl Into<api::ContentDetails> for ContentDetails {
@@ -41,23 +101,85 @@ impl Into<api::ContentDetails> for ContentDetails {
             ContentDetails::Image(image) => api::ContentDetails::Image(api::ImageContent {
                 src: image.src,
                 description: image.description,
+                media: image.media,
+                variants: Vec::new(),
+                captured_at: None,
+                latitude: None,
+                longitude: None,
             }),
             ContentDetails::Text(text) => api::ContentDetails::Text(api::TextContent {
                 title: text.title,
                 body: text.body,
             }),
+            ContentDetails::Audio(audio) => api::ContentDetails::Audio(api::AudioContent {
+                media: audio.media,
+                duration_ms: audio.duration_ms,
+                codec: audio.codec,
+                transcript: audio.transcript,
+            }),
+            ContentDetails::Video(video) => api::ContentDetails::Video(api::VideoContent {
+                media: video.media,
+                poster: video.poster,
+                duration_ms: video.duration_ms,
+                width: video.width,
+                height: video.height,
+                poster_url: None,
+            }),
+            ContentDetails::Location(location) => {
+                api::ContentDetails::Location(api::LocationContent {
+                    latitude: location.latitude,
+                    longitude: location.longitude,
+                    place_name: location.place_name,
+                    accuracy: location.accuracy,
+                })
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ImageContent {
     pub src: String,
     pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TextContent {
     pub title: String,
     pub body: String,
 }
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioContent {
+    pub media: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VideoContent {
+    pub media: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocationContent {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<f64>,
+}
@@ -1,5 +1,8 @@
-use chrono::{DateTime, Utc};
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::Passkey;
 
 mod content;
 pub use content::*;
@@ -16,6 +19,160 @@ pub struct Story {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub deleted: bool,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Incremented on every update, used to detect conflicting edits.
+    pub version: u32,
+}
+
+/// Where a story took place, kept in `story_locations` rather than on the story itself.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoryLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub place_name: Option<String>,
+}
+
+impl Into<api::StoryLocation> for StoryLocation {
+    fn into(self) -> api::StoryLocation {
+        api::StoryLocation {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            place_name: self.place_name,
+        }
+    }
+}
+
+/// A story with a location, as shown on the map.
+#[derive(Debug, Clone)]
+pub struct StoryPin {
+    pub uuid: Uuid,
+    pub title: String,
+    pub location: StoryLocation,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Into<api::StoryPin> for StoryPin {
+    fn into(self) -> api::StoryPin {
+        api::StoryPin {
+            uuid: self.uuid,
+            title: self.title,
+            location: self.location.into(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Stories sharing a cell of the grid the map is clustered on.
+#[derive(Debug, Clone)]
+pub struct StoryCell {
+    pub count: u32,
+    /// Average position of the cell's stories.
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Smallest area holding every story in the cell.
+    pub bbox: BoundingBox,
+    /// One of the cell's stories, the only one when `count` is 1.
+    /// Its `created_at` is that of the cell's newest story.
+    pub story: StoryPin,
+}
+
+impl Into<api::StoryCluster> for StoryCell {
+    fn into(self) -> api::StoryCluster {
+        api::StoryCluster {
+            count: self.count,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            bbox: self.bbox.into(),
+        }
+    }
+}
+
+/// An area of the map in WGS 84 degrees, e.g. `-122.52,37.70,-122.35,37.83` as `west,south,east,north`.
+/// A box crossing the antimeridian has a `west` greater than its `east`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    /// Degrees of longitude the box spans.
+    pub fn width(&self) -> f64 {
+        if self.crosses_antimeridian() {
+            self.east - self.west + 360.0
+        } else {
+            self.east - self.west
+        }
+    }
+
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west > self.east
+    }
+
+    /// Returns the parts of the box either side of the antimeridian, or just the box if it
+    /// doesn't cross it, since stored coordinates can only be matched against boxes that don't.
+    pub fn split_at_antimeridian(&self) -> Vec<BoundingBox> {
+        if !self.crosses_antimeridian() {
+            return vec![*self];
+        }
+
+        vec![
+            BoundingBox {
+                east: 180.0,
+                ..*self
+            },
+            BoundingBox {
+                west: -180.0,
+                ..*self
+            },
+        ]
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f64>().map_err(|_| ()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (west, south, east, north) = match values[..] {
+            [west, south, east, north] => (west, south, east, north),
+            _ => return Err(()),
+        };
+        let longitudes = -180.0..=180.0;
+        let latitudes = -90.0..=90.0;
+        if !longitudes.contains(&west)
+            || !longitudes.contains(&east)
+            || !latitudes.contains(&south)
+            || !latitudes.contains(&north)
+            || south > north
+        {
+            return Err(());
+        }
+
+        Ok(Self {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
+
+impl Into<api::BoundingBox> for BoundingBox {
+    fn into(self) -> api::BoundingBox {
+        api::BoundingBox {
+            west: self.west,
+            south: self.south,
+            east: self.east,
+            north: self.north,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +195,356 @@ impl Into<api::User> for User {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: Uuid,
+    pub name: String,
+    pub passkey: Passkey,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Into<api::Credential> for Credential {
+    fn into(self) -> api::Credential {
+        api::Credential {
+            uuid: self.uuid,
+            name: self.name,
+            last_used_at: self.last_used_at,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+/// What a request is allowed to do. Each scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read stories and the user's profile.
+    Read,
+    /// Create, update and delete stories.
+    Write,
+    /// Manage the account itself, e.g. passkeys, sessions and tokens.
+    /// Adding passkeys, replacing recovery codes and creating tokens also need a session.
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: Uuid,
+    pub name: String,
+    pub scope: Scope,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Into<api::ApiToken> for ApiToken {
+    fn into(self) -> api::ApiToken {
+        api::ApiToken {
+            uuid: self.uuid,
+            name: self.name,
+            scope: self.scope,
+            last_used_at: self.last_used_at,
+            created_at: self.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveryCode {
+    pub id: u32,
+    pub user_id: u32,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: Uuid,
+    pub device_label: Option<String>,
+    pub ip: Option<String>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Session {
+    /// Converts the session for the API, flagging whether it's the one making the request.
+    pub fn into_api(self, current: Option<Uuid>) -> api::Session {
+        api::Session {
+            current: current == Some(self.uuid),
+            uuid: self.uuid,
+            device_label: self.device_label,
+            ip: self.ip,
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// An uploaded file, its bytes are kept in the blob store under `storage_key`.
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: Uuid,
+    pub content_type: String,
+    pub size: u64,
+    pub storage_key: String,
+    pub metadata: MediaMetadata,
+    pub probe: MediaProbe,
+    /// Storage key of a frame extracted from video, served as its poster.
+    pub poster_storage_key: Option<String>,
+    /// Set when no poster could be extracted from the video, which isn't retried.
+    pub poster_failed_at: Option<DateTime<Utc>>,
+    /// Storage key and size of a copy of the file without its metadata, if one could be made.
+    pub stripped: Option<(String, u64)>,
+    /// Whether the owner opted in to being served the file with its metadata.
+    pub keep_metadata: bool,
+    /// Hex encoded SHA-256 of the upload, used to deduplicate the user's uploads.
+    pub sha256: Option<String>,
+    /// Number of content rows referring to the media.
+    pub ref_count: u32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Media {
+    /// Storage key and size of the file to serve as the original.
+    /// Returns nothing for an image whose metadata couldn't be removed,
+    /// the file is never served with metadata its owner didn't opt in to.
+    /// Audio and video are served as uploaded.
+    pub fn served(&self) -> Option<(&str, u64)> {
+        match (&self.stripped, self.keep_metadata) {
+            (_, true) => Some((&self.storage_key, self.size)),
+            (Some((storage_key, size)), false) => Some((storage_key, *size)),
+            (None, false) if self.content_type.starts_with("image/") => None,
+            (None, false) => Some((&self.storage_key, self.size)),
+        }
+    }
+}
+
+impl Into<api::Media> for Media {
+    fn into(self) -> api::Media {
+        api::Media {
+            url: api::Media::url(self.uuid),
+            uuid: self.uuid,
+            content_type: self.content_type,
+            size: self.size,
+            captured_at: self.metadata.captured_at,
+            latitude: self.metadata.latitude,
+            longitude: self.metadata.longitude,
+            duration_ms: self.probe.duration_ms,
+            codec: self.probe.codec,
+            width: self.probe.width,
+            height: self.probe.height,
+            poster_url: self
+                .poster_storage_key
+                .map(|_| api::Media::poster_url(self.uuid)),
+            sha256: self.sha256,
+            references: self.ref_count,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Read from the file's EXIF on upload.
+#[derive(Debug, Clone, Default)]
+pub struct MediaMetadata {
+    /// Local time on the camera's clock, EXIF doesn't reliably record a timezone.
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub orientation: Option<u16>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+/// Read from the container of audio and video on upload.
+#[derive(Debug, Clone, Default)]
+pub struct MediaProbe {
+    pub duration_ms: Option<u64>,
+    pub codec: Option<String>,
+    /// Only set for video.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Resized copies generated for uploaded images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariantSize {
+    Thumb,
+    Medium,
+    Full,
+}
+
+impl VariantSize {
+    pub const ALL: [VariantSize; 3] = [VariantSize::Thumb, VariantSize::Medium, VariantSize::Full];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VariantSize::Thumb => "thumb",
+            VariantSize::Medium => "medium",
+            VariantSize::Full => "full",
+        }
+    }
+
+    /// Longest edge of the variant in pixels. Images are never scaled up.
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            VariantSize::Thumb => 256,
+            VariantSize::Medium => 1024,
+            VariantSize::Full => 2048,
+        }
+    }
+}
+
+impl FromStr for VariantSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thumb" => Ok(VariantSize::Thumb),
+            "medium" => Ok(VariantSize::Medium),
+            "full" => Ok(VariantSize::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaVariant {
+    pub id: u32,
+    pub media_id: u32,
+    pub size: VariantSize,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+    pub byte_size: u64,
+    pub storage_key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl MediaVariant {
+    pub fn into_api(self, media_uuid: Uuid) -> api::MediaVariant {
+        api::MediaVariant {
+            size: self.size,
+            url: format!(
+                "{}?size={}",
+                api::Media::url(media_uuid),
+                self.size.as_str()
+            ),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// A resumable upload in progress, assembled from chunks kept in the blob store.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub id: u32,
+    pub user_id: u32,
+    pub uuid: Uuid,
+    pub content_type: String,
+    /// Total size declared when the upload was created.
+    pub size: u64,
+    /// Bytes received so far, where the next chunk has to start.
+    pub received: u64,
+    /// Hex encoded SHA-256 the assembled file is checked against.
+    pub sha256: String,
+    pub keep_metadata: bool,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Into<api::Upload> for Upload {
+    fn into(self) -> api::Upload {
+        api::Upload {
+            uuid: self.uuid,
+            content_type: self.content_type,
+            size: self.size,
+            offset: self.received,
+            expires_at: self.expires_at,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Snapshot of a story at one of its versions.
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub id: u32,
+    pub story_id: u32,
+    pub version: u32,
+    pub title: String,
+    /// The story's content, in order.
+    pub content: Vec<RevisionContent>,
+    pub location: Option<StoryLocation>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Into<api::RevisionSummary> for Revision {
+    fn into(self) -> api::RevisionSummary {
+        api::RevisionSummary {
+            version: self.version,
+            title: self.title,
+            created_at: self.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevisionContent {
+    pub uuid: Uuid,
+    pub details: ContentDetails,
+}
+
+impl Into<api::RevisionContent> for RevisionContent {
+    fn into(self) -> api::RevisionContent {
+        api::RevisionContent {
+            uuid: self.uuid,
+            details: self.details.into(),
+        }
+    }
+}
+
 pub struct Prompt {
     pub id: u32,
     pub uuid: Uuid,
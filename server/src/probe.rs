@@ -0,0 +1,74 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use mp4::{Mp4Reader, TrackType};
+
+use symphonia::core::{
+    codecs::CODEC_TYPE_NULL, errors::Error as SymphoniaError, formats::FormatOptions,
+    io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use crate::model;
+
+/// Reads the container of an audio upload, failing unless it holds an audio track.
+/// The file is read from disk, only as far as needed to find its tracks.
+pub fn probe_audio(content_type: &str, path: &Path) -> Result<model::MediaProbe, SymphoniaError> {
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    hint.mime_type(content_type);
+
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+    let params = &track.codec_params;
+
+    let duration_ms = params
+        .time_base
+        .zip(params.n_frames)
+        .map(|(time_base, frames)| {
+            let time = time_base.calc_time(frames);
+            time.seconds * 1000 + (time.frac * 1000.0) as u64
+        });
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|codec| codec.short_name.to_string());
+
+    Ok(model::MediaProbe {
+        duration_ms,
+        codec,
+        ..Default::default()
+    })
+}
+
+/// Reads the container of an MP4 or QuickTime upload, failing unless it holds a video track.
+/// The file is read from disk since only the boxes describing the tracks are needed.
+pub fn probe_video(path: &Path) -> Result<model::MediaProbe, mp4::Error> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let reader = Mp4Reader::read_header(BufReader::new(file), size)?;
+
+    let track = reader
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(TrackType::Video)))
+        .ok_or(mp4::Error::InvalidData("no video track"))?;
+
+    Ok(model::MediaProbe {
+        duration_ms: Some(reader.duration().as_millis() as u64),
+        codec: track
+            .media_type()
+            .ok()
+            .map(|media_type| media_type.to_string()),
+        width: Some(track.width().into()),
+        height: Some(track.height().into()),
+    })
+}
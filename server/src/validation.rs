@@ -0,0 +1,215 @@
+use std::ops::Deref;
+
+use async_trait::async_trait;
+use axum::{
+    body::HttpBody,
+    extract::FromRequest,
+    http::Request,
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use webauthn_rs::prelude::Url;
+
+use crate::{
+    api,
+    error::{self, ErrorCode},
+    AppError,
+};
+
+/// Matches `stories.title`.
+pub const MAX_STORY_TITLE_LENGTH: usize = 100;
+/// Matches `users.name`.
+pub const MAX_USER_NAME_LENGTH: usize = 30;
+/// Matches `credentials.name`.
+pub const MAX_CREDENTIAL_NAME_LENGTH: usize = 50;
+/// Matches `api_tokens.name`.
+pub const MAX_API_TOKEN_NAME_LENGTH: usize = 50;
+pub const MAX_CONTENT_BLOCKS: usize = 50;
+pub const MAX_IMAGE_SRC_LENGTH: usize = 2048;
+pub const MAX_IMAGE_DESCRIPTION_LENGTH: usize = 1000;
+pub const MAX_TEXT_TITLE_LENGTH: usize = 100;
+pub const MAX_TEXT_BODY_LENGTH: usize = 20_000;
+pub const MAX_AUDIO_TRANSCRIPT_LENGTH: usize = 20_000;
+/// Matches `story_locations.place_name` and `content_locations.place_name`.
+pub const MAX_PLACE_NAME_LENGTH: usize = 255;
+/// Schemes an image's `src` may use.
+pub const ALLOWED_IMAGE_SCHEMES: [&str; 2] = ["https", "http"];
+
+/// A request payload that can check itself before it reaches the database.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    /// Path to the offending field, e.g. `content[2].src`.
+    pub field: String,
+    pub message: String,
+}
+
+/// Every problem found with a payload, returned to the client as a `422 Unprocessable Entity`.
+#[derive(Debug, Default)]
+pub struct ValidationErrors(Vec<FieldError>);
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Checks that `value` has at most `max` characters, and any when `required`.
+    pub fn check_length(&mut self, field: &str, value: &str, required: bool, max: usize) {
+        if required && value.trim().is_empty() {
+            self.add(field, "Must not be empty.");
+        } else if value.chars().count() > max {
+            self.add(field, format!("Must be at most {} characters.", max));
+        }
+    }
+
+    /// Checks that the coordinates are WGS 84 degrees.
+    pub fn check_coordinates(&mut self, field: &str, latitude: f64, longitude: f64) {
+        if !(-90.0..=90.0).contains(&latitude) {
+            self.add(format!("{}.latitude", field), "Must be between -90 and 90.");
+        }
+        if !(-180.0..=180.0).contains(&longitude) {
+            self.add(
+                format!("{}.longitude", field),
+                "Must be between -180 and 180.",
+            );
+        }
+    }
+
+    pub fn check_story_location(&mut self, field: &str, location: &api::StoryLocation) {
+        self.check_coordinates(field, location.latitude, location.longitude);
+        if let Some(place_name) = &location.place_name {
+            self.check_length(
+                &format!("{}.place_name", field),
+                place_name,
+                false,
+                MAX_PLACE_NAME_LENGTH,
+            );
+        }
+    }
+
+    pub fn check_content(&mut self, field: &str, content: &api::ContentDetails) {
+        match content {
+            api::ContentDetails::Image(image) => {
+                // Uploaded images are referenced by `media`, `src` is only required without it.
+                let src = format!("{}.src", field);
+                let required = image.media.is_none();
+                self.check_length(&src, &image.src, required, MAX_IMAGE_SRC_LENGTH);
+                match Url::parse(&image.src) {
+                    _ if !required && image.src.is_empty() => {}
+                    Ok(url) if ALLOWED_IMAGE_SCHEMES.contains(&url.scheme()) => {}
+                    Ok(_) => self.add(
+                        src,
+                        format!("Must use one of {}.", ALLOWED_IMAGE_SCHEMES.join(", ")),
+                    ),
+                    Err(_) => self.add(src, "Must be a valid URL."),
+                }
+                self.check_length(
+                    &format!("{}.description", field),
+                    &image.description,
+                    false,
+                    MAX_IMAGE_DESCRIPTION_LENGTH,
+                );
+            }
+            api::ContentDetails::Text(text) => {
+                self.check_length(
+                    &format!("{}.title", field),
+                    &text.title,
+                    false,
+                    MAX_TEXT_TITLE_LENGTH,
+                );
+                self.check_length(
+                    &format!("{}.body", field),
+                    &text.body,
+                    false,
+                    MAX_TEXT_BODY_LENGTH,
+                );
+            }
+            api::ContentDetails::Audio(audio) => {
+                if let Some(transcript) = &audio.transcript {
+                    self.check_length(
+                        &format!("{}.transcript", field),
+                        transcript,
+                        false,
+                        MAX_AUDIO_TRANSCRIPT_LENGTH,
+                    );
+                }
+            }
+            // Video only refers to media, which is checked once it's looked up.
+            api::ContentDetails::Video(_) => {}
+            api::ContentDetails::Location(location) => {
+                self.check_coordinates(field, location.latitude, location.longitude);
+                self.check_length(
+                    &format!("{}.place_name", field),
+                    &location.place_name,
+                    false,
+                    MAX_PLACE_NAME_LENGTH,
+                );
+                match location.accuracy {
+                    Some(accuracy) if !(accuracy >= 0.0 && accuracy.is_finite()) => self.add(
+                        format!("{}.accuracy", field),
+                        "Must be a distance in meters.",
+                    ),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+}
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        error::error_response(
+            ErrorCode::ValidationFailed,
+            "Request is invalid.".into(),
+            self.0,
+        )
+    }
+}
+
+/// A JSON body that has passed [Validate].
+/// Malformed JSON is rejected with `400 Bad Request`, invalid payloads with `422 Unprocessable Entity`.
+pub struct ValidJson<T>(pub T);
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for ValidJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(|rejection| AppError::from(rejection).into_response())?;
+
+        value.validate().map_err(|errors| errors.into_response())?;
+
+        Ok(ValidJson(value))
+    }
+}